@@ -0,0 +1,58 @@
+//! Times the same programs walked word by word and run compiled.
+
+extern crate backforth;
+
+use std::time::{Duration, Instant};
+
+use backforth::*;
+
+mod programs;
+
+use programs::{COUNTDOWN, FACTORIAL};
+
+const FIB: &str = "
+fib = {
+    if > 2 pick 2 {} {
+        + fib + -2 swap fib + -1 dup
+    }
+}
+
+drop fib 15
+";
+
+const GUARDED: &str = "
+guarded = {
+    try { / 0 } { drop }
+}
+
+drop guarded guarded guarded guarded guarded guarded guarded guarded 4
+";
+
+fn time(source: &str, compiled: bool, rounds: u32) -> Duration {
+    let program = parse(source).unwrap();
+    let mut shell = Shell::new();
+    shell.set_compiled(compiled);
+
+    let start = Instant::now();
+    for _ in 0 .. rounds {
+        shell.load(program.clone().into_iter());
+        shell.run().unwrap();
+    }
+    start.elapsed() / rounds
+}
+
+fn main() {
+    let programs = [
+        ("factorial", FACTORIAL, 2000),
+        ("countdown", COUNTDOWN, 20),
+        ("fib", FIB, 20),
+        ("guarded", GUARDED, 20000),
+    ];
+
+    println!("{:<10} {:>12} {:>12}", "", "tree", "compiled");
+    for &(name, source, rounds) in &programs {
+        let tree = time(source, false, rounds);
+        let compiled = time(source, true, rounds);
+        println!("{:<10} {:>12?} {:>12?}", name, tree, compiled);
+    }
+}
@@ -0,0 +1,28 @@
+//! Times programs dominated by calls to interpreted words.
+
+extern crate backforth;
+
+use std::time::{Duration, Instant};
+
+use backforth::*;
+
+mod programs;
+
+use programs::{COUNTDOWN, FACTORIAL};
+
+fn time(source: &str, rounds: u32) -> Duration {
+    let program = parse(source).unwrap();
+    let mut shell = Shell::new();
+
+    let start = Instant::now();
+    for _ in 0 .. rounds {
+        shell.load(program.clone().into_iter());
+        shell.run().unwrap();
+    }
+    start.elapsed() / rounds
+}
+
+fn main() {
+    println!("factorial: {:>10?} per run", time(FACTORIAL, 2000));
+    println!("countdown: {:>10?} per run", time(COUNTDOWN, 20));
+}
@@ -0,0 +1,26 @@
+//! Programs timed by more than one benchmark.
+
+pub const FACTORIAL: &str = "
+factorial = {
+    dup
+    while { < 1 dup } {
+        + -1
+        dup
+        * rot
+        swap
+    }
+    drop
+}
+
+drop factorial 12
+";
+
+pub const COUNTDOWN: &str = "
+countdown = {
+    while { < 0 dup } {
+        drop dup + -1
+    }
+}
+
+drop countdown 2000
+";
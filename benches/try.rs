@@ -0,0 +1,42 @@
+//! Times `try` against the size of the program state it protects.
+//!
+//! Entering a `try` should cost the same whether the dictionary and the
+//! data stack hold ten entries or ten thousand.
+
+extern crate backforth;
+
+use std::time::Instant;
+
+use backforth::*;
+
+const ROUNDS: usize = 2000;
+
+fn setup(size: usize) -> Shell {
+    let mut source = String::new();
+
+    for i in 0 .. size {
+        source.push_str(&format!("word{} = {{ + {} 1 }}\n", i, i));
+        source.push_str(&format!("{}\n", i));
+    }
+
+    let mut shell = Shell::new();
+    shell.load(parse(&source).unwrap().into_iter());
+    shell.run().unwrap();
+    shell
+}
+
+fn main() {
+    let program = parse(&"try { drop dup } {}\n".repeat(ROUNDS)).unwrap();
+
+    for &size in &[10, 100, 1000, 10000] {
+        let mut shell = setup(size);
+        shell.load(program.clone().into_iter());
+
+        let start = Instant::now();
+        shell.run().unwrap();
+        let elapsed = start.elapsed();
+
+        let per_try = elapsed / ROUNDS as u32;
+        println!("state size {:>5}: {:>10?} per try", size, per_try);
+    }
+}
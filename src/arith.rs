@@ -0,0 +1,313 @@
+//! Arithmetic on numeric words.
+//!
+//! Two integers give an integer, and a float with any other number gives
+//! a float. Hex operands count as integers, all the way up to `#ffffffff`,
+//! so integer arithmetic is worked out in `i64` before narrowing. Integer results that do not
+//! fit an `i32` raise `EvalErr::Overflow`, unless the `bigint` feature is
+//! on, in which case they become arbitrary-precision `Word::Big`s. A big
+//! result small enough for an `i32` is always turned back into an `Int`,
+//! so each number has only one representation. Big results are capped at
+//! `MAX_BITS` and overflow beyond that, which is checked before they are
+//! worked out, so that no single word can take long to run.
+//!
+//! Comparisons, `min`, `max` and `==` go by value across every numeric
+//! kind, so `== 1 #1` and `== 1 1.0` both hold, while a number never
+//! equals a word of any other type.
+//!
+//! Bitwise operations work on hex instead. Non-negative integers are
+//! taken as hex, and the result is always hex.
+
+use std::cmp::Ordering;
+
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
+#[cfg(feature = "bigint")]
+use num_traits::{Signed, ToPrimitive, Zero};
+
+use super::*;
+
+/// The most bits a big integer result may take.
+#[cfg(feature = "bigint")]
+const MAX_BITS: u64 = 1 << 16;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Bitwise {
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+/// Both operands, promoted to a common kind.
+enum Pair {
+    Int(i64, i64),
+    #[cfg(feature = "bigint")]
+    Big(BigInt, BigInt),
+    Float(f64, f64),
+}
+
+fn pair(lhs: Word, rhs: Word) -> Result<Pair, EvalErr> {
+    if matches!(lhs, Word::Float(_)) || matches!(rhs, Word::Float(_)) {
+        return Ok(Pair::Float(lhs.into_float()?, rhs.into_float()?));
+    }
+
+    #[cfg(feature = "bigint")]
+    if matches!(lhs, Word::Big(_)) || matches!(rhs, Word::Big(_)) {
+        return Ok(Pair::Big(lhs.into_big()?, rhs.into_big()?));
+    }
+
+    Ok(Pair::Int(wide(lhs)?, wide(rhs)?))
+}
+
+pub(crate) fn arith(op: Arith, lhs: Word, rhs: Word) -> Result<Word, EvalErr> {
+    match pair(lhs, rhs)? {
+        Pair::Float(x, y) => float(op, x, y).map(Word::Float),
+
+        Pair::Int(x, y) => {
+            let z = match op {
+                Arith::Add => x.checked_add(y),
+                Arith::Sub => x.checked_sub(y),
+                Arith::Mul => x.checked_mul(y),
+                Arith::Div | Arith::Rem if y == 0 => {
+                    return Err(EvalErr::DivideByZero);
+                },
+                Arith::Div => x.checked_div(y),
+                Arith::Rem => x.checked_rem(y),
+                Arith::Pow => x.checked_pow(exponent(y)?),
+            };
+
+            match z.and_then(|z| i32::try_from(z).ok()) {
+                Some(z) => Ok(Word::Int(z)),
+                None => overflowed(op, x, y),
+            }
+        },
+
+        #[cfg(feature = "bigint")]
+        Pair::Big(x, y) => big(op, x, y),
+    }
+}
+
+pub(crate) fn abs(word: Word) -> Result<Word, EvalErr> {
+    match word {
+        Word::Float(f) => Ok(Word::Float(f.abs())),
+
+        #[cfg(feature = "bigint")]
+        Word::Big(b) => Ok(Word::from(b.abs())),
+
+        other => {
+            narrow(wide(other)?.abs())
+        },
+    }
+}
+
+pub(crate) fn negate(word: Word) -> Result<Word, EvalErr> {
+    match word {
+        Word::Float(f) => Ok(Word::Float(-f)),
+
+        #[cfg(feature = "bigint")]
+        Word::Big(b) => Ok(Word::from(-b)),
+
+        other => {
+            narrow(-wide(other)?)
+        },
+    }
+}
+
+pub(crate) fn compare(lhs: Word, rhs: Word) -> Result<Ordering, EvalErr> {
+    Ok(match pair(lhs, rhs)? {
+        Pair::Int(x, y) => x.cmp(&y),
+        #[cfg(feature = "bigint")]
+        Pair::Big(x, y) => x.cmp(&y),
+        // Floats are never NaN, so this always has an answer.
+        Pair::Float(x, y) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+    })
+}
+
+/// Whether two words have the same value, if both are numbers. Other
+/// words are left to plain equality.
+pub(crate) fn same_value(lhs: &Word, rhs: &Word) -> Option<bool> {
+    if !numeric(lhs) || !numeric(rhs) {
+        return None;
+    }
+
+    compare(lhs.clone(), rhs.clone()).ok().map(Ordering::is_eq)
+}
+
+fn numeric(word: &Word) -> bool {
+    match *word {
+        Word::Int(_) | Word::Hex(_) | Word::Float(_) => true,
+        #[cfg(feature = "bigint")]
+        Word::Big(_) => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn bitwise(op: Bitwise, lhs: Word, rhs: Word) -> Result<Word, EvalErr> {
+    let x = bits(lhs)?;
+    let y = bits(rhs)?;
+
+    // Shifting every bit out leaves nothing, rather than wrapping.
+    Ok(Word::Hex(match op {
+        Bitwise::And => x & y,
+        Bitwise::Or => x | y,
+        Bitwise::Xor => x ^ y,
+        Bitwise::Shl => x.checked_shl(y).unwrap_or(0),
+        Bitwise::Shr => x.checked_shr(y).unwrap_or(0),
+    }))
+}
+
+pub(crate) fn invert(word: Word) -> Result<Word, EvalErr> {
+    Ok(Word::Hex(!bits(word)?))
+}
+
+/// The hex a bitwise operand stands for. Floats have no bits to speak of.
+fn bits(word: Word) -> Result<u32, EvalErr> {
+    match word {
+        Word::Float(_) => Err(EvalErr::WrongType(word, TypeName::Hex)),
+        other => other.into_hex(),
+    }
+}
+
+/// An integer operand, with hex above `i32::MAX` kept whole.
+fn wide(word: Word) -> Result<i64, EvalErr> {
+    match word {
+        Word::Hex(h) => Ok(i64::from(h)),
+        other => other.into_int().map(i64::from),
+    }
+}
+
+/// An integer result that may not fit an `i32`.
+fn narrow(z: i64) -> Result<Word, EvalErr> {
+    match i32::try_from(z) {
+        Ok(z) => Ok(Word::Int(z)),
+        Err(_) => overflowed(Arith::Add, z, 0),
+    }
+}
+
+/// Integer powers are only defined for exponents of zero and up. Only
+/// negative integers fail, so the word is rebuilt as one.
+fn exponent(y: i64) -> Result<u32, EvalErr> {
+    u32::try_from(y).map_err(|_| EvalErr::CantCoerce(Word::Int(y as i32), TypeName::Hex))
+}
+
+fn float(op: Arith, x: f64, y: f64) -> Result<f64, EvalErr> {
+    let z = match op {
+        Arith::Add => x + y,
+        Arith::Sub => x - y,
+        Arith::Mul => x * y,
+        Arith::Div | Arith::Rem if y == 0.0 => {
+            return Err(EvalErr::DivideByZero);
+        },
+        Arith::Div => x / y,
+        Arith::Rem => x % y,
+        Arith::Pow => x.powf(y),
+    };
+
+    finite(z)
+}
+
+/// Floats never hold infinities or NaN, so that every one of them can be
+/// written as a literal and equals itself.
+pub(crate) fn finite(f: f64) -> Result<f64, EvalErr> {
+    if f.is_finite() {
+        Ok(f)
+    } else {
+        Err(EvalErr::Overflow)
+    }
+}
+
+#[cfg(not(feature = "bigint"))]
+fn overflowed(_: Arith, _: i64, _: i64) -> Result<Word, EvalErr> {
+    Err(EvalErr::Overflow)
+}
+
+#[cfg(feature = "bigint")]
+fn overflowed(op: Arith, x: i64, y: i64) -> Result<Word, EvalErr> {
+    big(op, BigInt::from(x), BigInt::from(y))
+}
+
+#[cfg(feature = "bigint")]
+fn big(op: Arith, x: BigInt, y: BigInt) -> Result<Word, EvalErr> {
+    if big_bits(op, &x, &y) > MAX_BITS {
+        return Err(EvalErr::Overflow);
+    }
+
+    Ok(Word::from(match op {
+        Arith::Add => x + y,
+        Arith::Sub => x - y,
+        Arith::Mul => x * y,
+        Arith::Div | Arith::Rem if y.is_zero() => {
+            return Err(EvalErr::DivideByZero);
+        },
+        Arith::Div => x / y,
+        Arith::Rem => x % y,
+        Arith::Pow => match y.to_i64() {
+            Some(y) => x.pow(exponent(y)?),
+            None => return Err(EvalErr::Overflow),
+        },
+    }))
+}
+
+/// An upper bound on the bits in the result, found without working it out.
+#[cfg(feature = "bigint")]
+fn big_bits(op: Arith, x: &BigInt, y: &BigInt) -> u64 {
+    match op {
+        Arith::Add | Arith::Sub => x.bits().max(y.bits()) + 1,
+        Arith::Mul => x.bits() + y.bits(),
+        Arith::Div | Arith::Rem => x.bits(),
+        // Zero, one and minus one stay that size whatever the exponent.
+        Arith::Pow if x.bits() <= 1 => 1,
+        Arith::Pow => x.bits().saturating_mul(y.to_u64().unwrap_or(u64::MAX)),
+    }
+}
+
+#[cfg(feature = "bigint")]
+impl From<BigInt> for Word {
+    fn from(b: BigInt) -> Self {
+        match b.to_i32() {
+            Some(i) => Word::Int(i),
+            None => Word::Big(b),
+        }
+    }
+}
+
+#[cfg(feature = "bigint")]
+impl TryFrom<Word> for BigInt {
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        word.into_big()
+    }
+}
+
+#[cfg(feature = "bigint")]
+impl Word {
+    pub(crate) fn into_big(self) -> Result<BigInt, EvalErr> {
+        match self {
+            Word::Big(b) => Ok(b),
+            Word::Hex(h) => Ok(BigInt::from(h)),
+            other => other.into_int().map(BigInt::from),
+        }
+    }
+}
+
+#[cfg(feature = "bigint")]
+pub(crate) fn big_to_float(b: &BigInt) -> Result<f64, EvalErr> {
+    finite(b.to_f64().unwrap_or(f64::INFINITY))
+}
+
+#[cfg(feature = "bigint")]
+pub(crate) fn big_to_hex(b: &BigInt) -> Option<u32> {
+    b.to_u32()
+}
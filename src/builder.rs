@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::PathBuf;
+
+use super::*;
+
+/// Sets up a `Shell`: which builtins it has and under what names, and
+/// which preludes it runs before it is handed over.
+///
+/// ```
+/// use backforth::ShellBuilder;
+///
+/// let shell = ShellBuilder::new()
+///     .without("command")
+///     .rename("echo", "print")
+///     .prelude("greet.iv", "greet = { print strcat \"hello \" }")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ShellBuilder {
+    stdlib: bool,
+    preludes: Vec<Prelude>,
+    only: Option<Vec<String>>,
+    without: Vec<String>,
+    renames: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+enum Prelude {
+    Source(String, String),
+    File(PathBuf),
+}
+
+impl Default for ShellBuilder {
+    fn default() -> Self {
+        ShellBuilder::new()
+    }
+}
+
+impl ShellBuilder {
+    /// A builder for the same shell as `Shell::new`: every builtin
+    /// under its usual name, and the standard library.
+    pub fn new() -> Self {
+        ShellBuilder {
+            stdlib: true,
+            preludes: Vec::new(),
+            only: None,
+            without: Vec::new(),
+            renames: Vec::new(),
+        }
+    }
+
+    /// Whether to run the standard library before any other prelude.
+    pub fn stdlib(mut self, load: bool) -> Self {
+        self.stdlib = load;
+        self
+    }
+
+    /// Runs `source` once the shell is set up. `name` is what error
+    /// positions in it are reported against.
+    pub fn prelude<N, S>(mut self, name: N, source: S) -> Self
+        where N: Into<String>, S: Into<String>
+    {
+        self.preludes.push(Prelude::Source(name.into(), source.into()));
+        self
+    }
+
+    /// Reads and runs a file once the shell is set up. Preludes run in
+    /// the order they were added.
+    pub fn prelude_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.preludes.push(Prelude::File(path.into()));
+        self
+    }
+
+    /// Binds only the named builtins, leaving the rest out.
+    pub fn builtins<I, S>(mut self, names: I) -> Self
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.only = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Leaves a builtin out.
+    pub fn without<S: Into<String>>(mut self, name: S) -> Self {
+        self.without.push(name.into());
+        self
+    }
+
+    /// Binds a builtin under a different name instead of its own.
+    pub fn rename<S, T>(mut self, name: S, to: T) -> Self
+        where S: Into<String>, T: Into<String>
+    {
+        self.renames.push((name.into(), to.into()));
+        self
+    }
+
+    /// Builds the shell and runs its preludes. Naming a builtin that
+    /// does not exist is an error, as is any error a prelude raises.
+    ///
+    /// `popeh` is always bound, because `try` cannot work without it.
+    pub fn build(self) -> Result<Shell, EvalErr> {
+        let mut builtins = Builtin::default_bindings();
+
+        let unknown = |name: &str| EvalErr::CantUnderstand(name.to_owned());
+
+        if let Some(ref only) = self.only {
+            let only = only.iter().map(|name| {
+                let sym = Symbol::intern(name);
+                builtins.get(&sym).map(|_| sym).ok_or_else(|| unknown(name))
+            }).collect::<Result<Vec<_>, _>>()?;
+
+            builtins.retain(|name, _| only.contains(name));
+        }
+
+        for name in &self.without {
+            builtins.swap_remove(&Symbol::intern(name));
+        }
+
+        for (name, to) in &self.renames {
+            let binding = builtins.swap_remove(&Symbol::intern(name))
+                .ok_or_else(|| unknown(name))?;
+            builtins.insert(Symbol::intern(to), binding);
+        }
+
+        builtins.entry(Symbol::intern("popeh"))
+            .or_insert(Binding::Primitive(Builtin::PopEH));
+
+        let mut shell = Shell::with_dict(builtins);
+
+        if self.stdlib {
+            shell.load(parse_named("stdlib", STDLIB)?.into_iter());
+            shell.run()?;
+        }
+
+        for prelude in self.preludes {
+            let program = match prelude {
+                Prelude::Source(name, source) => parse_named(&name, &source)?,
+
+                Prelude::File(path) => {
+                    let name = path.to_string_lossy().into_owned();
+                    let source = fs::read_to_string(&path)
+                        .map_err(|err| EvalErr::io(&name, err))?;
+                    parse_named(&name, &source)?
+                },
+            };
+
+            shell.load(program.into_iter());
+            shell.run()?;
+        }
+
+        Ok(shell)
+    }
+}
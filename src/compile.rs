@@ -0,0 +1,654 @@
+//! The compiled backend.
+//!
+//! A definition's body is lowered once into a flat list of instructions.
+//! Most words become one instruction each: a literal push, a primitive
+//! resolved up front, or a name to look up when it runs. An `if` whose
+//! branches are literal lists, and `while` and `loop` given literal
+//! lists, become jumps instead, so their bodies run inline rather than
+//! in frames of their own and without rebuilding the loop every time
+//! round.
+//!
+//! Every instruction also records where the tree-walker would be at that
+//! point, so a compiled frame can always be unfolded back into ordinary
+//! frames. That happens when a primitive or a word the lowering stands
+//! in for is rebound while the frame runs, and when a macro reaches for
+//! a word that became a jump. Backtraces, `debug` and saved images see
+//! unfolded frames too. A compiled frame counts the rounds of each loop
+//! it is in, so that the callers a round would have replaced, which the
+//! tree-walker lists in backtraces, can be made up again.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use ordermap::OrderMap;
+
+use super::{parse_named, Binding, Builtin, Call, Frame, Span, Symbol, Word, STDLIB};
+use tail::Tail;
+
+/// A body lowered for the compiled backend.
+pub(crate) struct Compiled {
+    pub ops: Vec<Op>,
+
+    /// The shell's epoch when this was compiled. Resolved primitives
+    /// and lowered loops are only trusted while it still matches.
+    pub epoch: u64,
+
+    /// Where the tree-walker would be before each instruction, and once
+    /// they have all run.
+    places: Vec<Place>,
+
+    regions: Vec<Region>,
+
+    /// How many loops were lowered, each with a round counter of its own.
+    pub loops: usize,
+
+    /// Literal lists the body pushes, compiled in turn the first time
+    /// `eval`, `if` or `try` enters one.
+    lists: Vec<Literal>,
+}
+
+struct Literal {
+    list: Rc<VecDeque<Word>>,
+    code: RefCell<Option<Rc<Compiled>>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Op {
+    /// Push the word as a literal.
+    Push(Word),
+
+    /// Run a primitive, resolved when the body was compiled.
+    Prim(Builtin, Symbol, Option<Rc<Span>>),
+
+    /// Look the word up when it runs.
+    Call(Symbol, Option<Rc<Span>>),
+
+    /// An `if` whose branches were pushed as literals.
+    If(Box<Branch>),
+
+    /// Pop a condition, and continue at the target unless it holds.
+    Test(usize),
+
+    /// Continue at the target.
+    Jump(usize),
+
+    /// Start counting the rounds of a loop, and continue at the target.
+    Begin(usize, usize),
+
+    /// Count another round of a loop, and continue at the target.
+    Repeat(usize, usize),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Branch {
+    pub name: Symbol,
+    pub span: Option<Rc<Span>>,
+
+    /// The lists the branches were compiled from. Anything else on the
+    /// stack is left to `if` itself.
+    pub consequent: Rc<VecDeque<Word>>,
+    pub alternative: Rc<VecDeque<Word>>,
+
+    /// Where the alternative starts. The consequent starts two
+    /// instructions after the `If`, because the one in between is where
+    /// `if` itself carries on from.
+    pub otherwise: usize,
+}
+
+/// A body the tree-walker would be partway through, and how many of its
+/// words it would have left.
+#[derive(Copy, Clone, Debug)]
+struct Place {
+    region: usize,
+    left: usize,
+}
+
+/// A body whose words were lowered, or one the tree-walker would have
+/// built along the way, such as a loop's next round.
+struct Region {
+    body: Rc<VecDeque<Word>>,
+    outer: Option<Place>,
+    rounds: Option<Rounds>,
+}
+
+/// The callers the tree-walker's frame for a loop's round would have
+/// replaced: the loop word itself, and in each round before this one
+/// the words the round went through to start the next. `last` is for a
+/// frame that has started on the round's remainder.
+struct Rounds {
+    index: usize,
+    first: Call,
+    each: Vec<Call>,
+    last: Option<Call>,
+}
+
+impl Rounds {
+    fn tail(&self, tail: Option<Rc<Tail>>, rounds: usize) -> Option<Rc<Tail>> {
+        let tail = Tail::push(tail, self.first.clone());
+        let tail = Tail::repeat(tail, &self.each, rounds);
+
+        match self.last {
+            Some(ref call) => Tail::push(tail, call.clone()),
+            None => tail,
+        }
+    }
+}
+
+impl Compiled {
+    /// Whether a frame at `pc` has nothing left to do.
+    pub fn done(&self, pc: usize) -> bool {
+        match self.ops.get(pc) {
+            None => true,
+            Some(&Op::Jump(to)) => to >= self.ops.len(),
+            Some(_) => false,
+        }
+    }
+
+    /// The frames the tree-walker would have in place of `frame`, which
+    /// runs this body, outermost first. With `running` they are as they
+    /// would be while the instruction before `frame.pc` runs, and
+    /// otherwise as they would be once it has finished.
+    pub fn unfold(&self, frame: &Frame, running: bool) -> Vec<Frame> {
+        let place = match frame.pc.checked_sub(1).filter(|_| running) {
+            Some(pc) => self.places.get(pc).map(|&here| Place {
+                left: here.left.saturating_sub(1),
+                ..here
+            }),
+            None => self.places.get(frame.pc).copied(),
+        };
+
+        let mut places = Vec::new();
+        let mut place = place;
+
+        while let Some(here) = place {
+            places.push(here);
+            place = self.regions[here.region].outer;
+        }
+
+        let mut frames: Vec<Frame> = Vec::new();
+
+        for (i, here) in places.into_iter().rev().enumerate() {
+            let region = &self.regions[here.region];
+
+            // A frame with nothing left was dropped on the way into the
+            // next one, which took over its callers.
+            let mut tail = match frames.last() {
+                Some(outer) if outer.left == 0 => {
+                    let outer = frames.pop().unwrap();
+                    match outer.call {
+                        Some(call) => Tail::push(outer.tail, call),
+                        None => outer.tail,
+                    }
+                },
+                _ => None,
+            };
+
+            if i == 0 {
+                tail = frame.tail.clone();
+            }
+
+            if let Some(ref rounds) = region.rounds {
+                tail = rounds.tail(tail, frame.rounds[rounds.index]);
+            }
+
+            frames.push(Frame {
+                body: region.body.clone(),
+                left: here.left,
+                call: frame.call.clone().filter(|_| i == 0),
+                code: None,
+                pc: 0,
+                rounds: Vec::new(),
+                tail,
+            });
+        }
+
+        frames
+    }
+
+    /// The compiled form of a literal list this body pushes, if `body`
+    /// is one of them.
+    pub fn list(
+        &self,
+        body: &Rc<VecDeque<Word>>,
+        dict: &OrderMap<Symbol, Binding>,
+        epoch: u64,
+    ) -> Option<Rc<Compiled>> {
+        let literal = self.lists.iter().find(|literal| {
+            Rc::ptr_eq(&literal.list, body)
+        })?;
+
+        let mut code = literal.code.borrow_mut();
+        match *code {
+            Some(ref code) if code.epoch == epoch => Some(code.clone()),
+            _ => {
+                let fresh = Rc::new(compile(dict, body, epoch));
+                *code = Some(fresh.clone());
+                Some(fresh)
+            },
+        }
+    }
+}
+
+pub(crate) fn compile(
+    dict: &OrderMap<Symbol, Binding>,
+    body: &Rc<VecDeque<Word>>,
+    epoch: u64,
+) -> Compiled {
+    let mut lowering = Lowering {
+        dict,
+        templates: Loops::find(dict),
+        ops: Vec::new(),
+        places: Vec::new(),
+        regions: Vec::new(),
+        loops: 0,
+        lists: Vec::new(),
+    };
+
+    lowering.lower(body, None);
+    lowering.places.push(Place { region: 0, left: 0 });
+    lowering.thread_jumps();
+
+    Compiled {
+        ops: lowering.ops,
+        epoch,
+        places: lowering.places,
+        regions: lowering.regions,
+        loops: lowering.loops,
+        lists: lowering.lists.into_iter().map(|list| Literal {
+            list,
+            code: RefCell::new(None),
+        }).collect(),
+    }
+}
+
+/// Names whose meaning the lowering builds in. Rebinding any of them
+/// makes compiled bodies stale, just as rebinding a primitive does.
+pub(crate) fn lowered(name: Symbol) -> bool {
+    thread_local! {
+        static LOWERED: [Symbol; 6] = {
+            ["while", "loop", "when", "swap", "-rot", "rot"].map(Symbol::intern)
+        };
+    }
+
+    LOWERED.with(|lowered| lowered.contains(&name))
+}
+
+/// The loop templates from the standard library, when `while` and
+/// `loop` and everything they use still mean what they meant there.
+struct Loops {
+    while_: Option<Rc<VecDeque<Word>>>,
+    loop_: Option<Rc<VecDeque<Word>>>,
+}
+
+impl Loops {
+    fn find(dict: &OrderMap<Symbol, Binding>) -> Self {
+        let primitives = |names: &[(&str, Builtin)]| {
+            names.iter().all(|&(name, op)| {
+                matches!(dict.get(&Symbol::intern(name)),
+                    Some(&Binding::Primitive(bound)) if bound == op)
+            })
+        };
+
+        let both = primitives(&[
+            ("eval", Builtin::Eval),
+            ("expand", Builtin::Expand),
+        ]);
+
+        let while_deps = primitives(&[
+            ("if", Builtin::If),
+            ("roll", Builtin::Roll),
+        ]) && ["when", "swap", "-rot", "rot"].iter().all(|&name| {
+            stock(dict, name).is_some()
+        });
+
+        Loops {
+            while_: stock(dict, "while").filter(|_| both && while_deps)
+                .and_then(|def| template(&def)),
+            loop_: stock(dict, "loop").filter(|_| both)
+                .and_then(|def| template(&def)),
+        }
+    }
+}
+
+/// A name's definition, if it is still the one the standard library
+/// gives it.
+fn stock(dict: &OrderMap<Symbol, Binding>, name: &str) -> Option<Word> {
+    thread_local! {
+        static STOCK: HashMap<Symbol, Word> = {
+            let words: Vec<Word> = parse_named("stdlib", STDLIB).unwrap_or_default();
+
+            words.windows(3).filter_map(|triple| match *triple {
+                [Word::Atom(name, _), Word::Atom(assign, _), ref def]
+                    if assign.as_str() == "=" => Some((name, def.clone())),
+                _ => None,
+            }).collect()
+        };
+    }
+
+    let name = Symbol::intern(name);
+    let def = match dict.get(&name) {
+        Some(Binding::Interpreted(_, def)) => def,
+        _ => return None,
+    };
+
+    STOCK.with(|stock| {
+        stock.get(&name).filter(|&expected| expected == &**def).cloned()
+    })
+}
+
+/// The list a loop definition expands and runs each time round.
+fn template(def: &Word) -> Option<Rc<VecDeque<Word>>> {
+    match *def {
+        Word::List(ref body) => match body.back() {
+            Some(Word::List(template)) => Some(template.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fills in a loop template the way `expand` would.
+fn expand(template: &Rc<VecDeque<Word>>, args: &[(&str, &Rc<VecDeque<Word>>)])
+    -> Rc<VecDeque<Word>>
+{
+    let dict = args.iter().map(|&(name, list)| {
+        (Symbol::intern(name), Word::List(list.clone()))
+    }).collect();
+
+    Word::List(template.clone()).expand(&dict).into_list()
+}
+
+/// The call a loop makes to `name` in an expanded round, where its span
+/// points into the standard library.
+fn call_to(body: &VecDeque<Word>, name: &str) -> Call {
+    let span = body.iter().find_map(|word| match *word {
+        Word::Atom(atom, ref span) if atom.as_str() == name => Some(span.clone()),
+        _ => None,
+    });
+
+    Call {
+        name: Symbol::intern(name),
+        span: span.unwrap_or_default(),
+        tail: 0,
+    }
+}
+
+/// How many words of an expanded loop are left once its `eval` of the
+/// condition or body has started.
+fn after_eval(body: &VecDeque<Word>) -> usize {
+    body.iter().position(|word| {
+        matches!(*word, Word::Atom(name, _) if name.as_str() == "eval")
+    }).unwrap_or(0)
+}
+
+struct Lowering<'a> {
+    dict: &'a OrderMap<Symbol, Binding>,
+    templates: Loops,
+    ops: Vec<Op>,
+    places: Vec<Place>,
+    regions: Vec<Region>,
+    loops: usize,
+    lists: Vec<Rc<VecDeque<Word>>>,
+}
+
+impl<'a> Lowering<'a> {
+    fn emit(&mut self, op: Op, place: Place) -> usize {
+        self.ops.push(op);
+        self.places.push(place);
+        self.ops.len() - 1
+    }
+
+    fn region(&mut self, body: &Rc<VecDeque<Word>>, outer: Option<Place>)
+        -> usize
+    {
+        self.regions.push(Region { body: body.clone(), outer, rounds: None });
+        self.regions.len() - 1
+    }
+
+    fn round(&mut self, body: &Rc<VecDeque<Word>>, outer: Place, rounds: Rounds)
+        -> usize
+    {
+        self.regions.push(Region {
+            body: body.clone(),
+            outer: Some(outer),
+            rounds: Some(rounds),
+        });
+        self.regions.len() - 1
+    }
+
+    /// Lowers a body in the order its words run, which is from the end.
+    fn lower(&mut self, body: &Rc<VecDeque<Word>>, outer: Option<Place>) {
+        let region = self.region(body, outer);
+        let n = body.len();
+        let word = |j: usize| &body[n - 1 - j];
+
+        // Literal lists pushed so far, by position, which a later `if`
+        // may take as its branches.
+        let mut pushed: Vec<usize> = Vec::new();
+
+        let mut j = 0;
+        while j < n {
+            let here = Place { region, left: n - j };
+
+            if let (Some(body), Some(cond), Some(call)) =
+                (list(word(j)), word_at(body, n, j + 1).and_then(list),
+                 word_at(body, n, j + 2).and_then(|word| call(word, "while")))
+            {
+                if let Some(template) = self.templates.while_.clone() {
+                    let after = Place { region, left: n - j - 3 };
+                    self.lower_while(here, after, &template, call, cond, body);
+                    j += 3;
+                    continue;
+                }
+            }
+
+            if let (Some(body), Some(call)) =
+                (list(word(j)), word_at(body, n, j + 1).and_then(|word| call(word, "loop")))
+            {
+                if let Some(template) = self.templates.loop_.clone() {
+                    let after = Place { region, left: n - j - 2 };
+                    self.lower_loop(here, after, &template, call, body);
+                    j += 2;
+                    continue;
+                }
+            }
+
+            match *word(j) {
+                Word::Atom(name, ref span) => match self.dict.get(&name) {
+                    Some(&Binding::Primitive(Builtin::If)) => {
+                        let branches = pushed.windows(2).rposition(|pair| {
+                            pair[0] + 1 == pair[1]
+                        });
+
+                        match branches {
+                            Some(k) => {
+                                let alternative = list(word(pushed[k])).unwrap();
+                                let consequent = list(word(pushed[k + 1])).unwrap();
+                                pushed.truncate(k);
+
+                                let after = Place { region, left: n - j - 1 };
+                                self.lower_if(here, after, name, span, consequent, alternative);
+                            },
+
+                            None => {
+                                self.emit(Op::Prim(Builtin::If, name, span.clone()), here);
+                            },
+                        }
+                    },
+
+                    Some(&Binding::Primitive(op)) => {
+                        self.emit(Op::Prim(op, name, span.clone()), here);
+                    },
+
+                    _ => {
+                        self.emit(Op::Call(name, span.clone()), here);
+                    },
+                },
+
+                Word::List(ref items) => {
+                    pushed.push(j);
+                    self.lists.push(items.clone());
+                    self.emit(Op::Push(word(j).clone()), here);
+                },
+
+                ref other => {
+                    self.emit(Op::Push(other.clone()), here);
+                },
+            }
+
+            j += 1;
+        }
+    }
+
+    fn lower_if(
+        &mut self,
+        here: Place,
+        after: Place,
+        name: Symbol,
+        span: &Option<Rc<Span>>,
+        consequent: &Rc<VecDeque<Word>>,
+        alternative: &Rc<VecDeque<Word>>,
+    ) {
+        let at = self.emit(Op::Jump(0), here);
+        let fallback = self.emit(Op::Jump(0), after);
+
+        self.lower(consequent, Some(after));
+        let skip = self.emit(Op::Jump(0), after);
+
+        let otherwise = self.ops.len();
+        self.lower(alternative, Some(after));
+        let end = self.ops.len();
+
+        self.ops[fallback] = Op::Jump(end);
+        self.ops[skip] = Op::Jump(end);
+        self.ops[at] = Op::If(Box::new(Branch {
+            name,
+            span: span.clone(),
+            consequent: consequent.clone(),
+            alternative: alternative.clone(),
+            otherwise,
+        }));
+    }
+
+    /// Lays out `while COND BODY` as the condition, a test, the body and
+    /// a jump back. Meanwhile the tree-walker would be inside the round
+    /// the standard library expands, so that is what the places record.
+    fn lower_while(
+        &mut self,
+        here: Place,
+        after: Place,
+        template: &Rc<VecDeque<Word>>,
+        call: Call,
+        cond: &Rc<VecDeque<Word>>,
+        body: &Rc<VecDeque<Word>>,
+    ) {
+        let round = expand(template, &[("COND", cond), ("BODY", body)]);
+        let rest = round.iter().find_map(list).cloned().unwrap_or_default();
+
+        let index = self.loops;
+        self.loops += 1;
+
+        let start = self.ops.len() + 1;
+        self.emit(Op::Begin(index, start), here);
+
+        // Each round goes through `when` into its remainder, which calls
+        // `while` again for the next.
+        let when = call_to(&round, "when");
+        let each = vec![when.clone(), call_to(&rest, "while")];
+
+        let round = self.round(&round, after, Rounds {
+            index,
+            first: call.clone(),
+            each: each.clone(),
+            last: None,
+        });
+        let testing = Place { region: round, left: after_eval(&self.regions[round].body) };
+        self.lower(cond, Some(testing));
+        let test = self.emit(Op::Test(0), testing);
+
+        let rest = self.round(&rest, after, Rounds {
+            index,
+            first: call,
+            each,
+            last: Some(when),
+        });
+        let again = Place { region: rest, left: after_eval(&self.regions[rest].body) };
+        self.lower(body, Some(again));
+        self.emit(Op::Repeat(index, start), again);
+
+        self.ops[test] = Op::Test(self.ops.len());
+    }
+
+    /// Lays out `loop BODY` as the body and a jump back.
+    fn lower_loop(
+        &mut self,
+        here: Place,
+        after: Place,
+        template: &Rc<VecDeque<Word>>,
+        call: Call,
+        body: &Rc<VecDeque<Word>>,
+    ) {
+        let round = expand(template, &[("BODY", body)]);
+
+        let index = self.loops;
+        self.loops += 1;
+
+        let start = self.ops.len() + 1;
+        self.emit(Op::Begin(index, start), here);
+
+        let each = vec![call_to(&round, "loop")];
+        let round = self.round(&round, after, Rounds {
+            index,
+            first: call,
+            each,
+            last: None,
+        });
+        let again = Place { region: round, left: after_eval(&self.regions[round].body) };
+        self.lower(body, Some(again));
+        self.emit(Op::Repeat(index, start), again);
+    }
+
+    /// Points jumps that land on other jumps straight at the end of the
+    /// chain, so a branch that ends a body leaves its frame finished.
+    fn thread_jumps(&mut self) {
+        let target = |ops: &[Op], mut to: usize| {
+            for _ in 0 .. ops.len() {
+                match ops.get(to) {
+                    Some(&Op::Jump(next)) if next != to => to = next,
+                    _ => break,
+                }
+            }
+            to
+        };
+
+        for i in 0 .. self.ops.len() {
+            if let Op::Jump(to) = self.ops[i] {
+                self.ops[i] = Op::Jump(target(&self.ops, to));
+            }
+        }
+    }
+}
+
+fn word_at(body: &VecDeque<Word>, n: usize, j: usize) -> Option<&Word> {
+    if j < n { body.get(n - 1 - j) } else { None }
+}
+
+fn list(word: &Word) -> Option<&Rc<VecDeque<Word>>> {
+    match *word {
+        Word::List(ref items) => Some(items),
+        _ => None,
+    }
+}
+
+/// The call `word` makes, if it is the atom `name`.
+fn call(word: &Word, name: &str) -> Option<Call> {
+    match *word {
+        Word::Atom(atom, ref span) if atom.as_str() == name => Some(Call {
+            name: atom,
+            span: span.clone(),
+            tail: 0,
+        }),
+        _ => None,
+    }
+}
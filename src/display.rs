@@ -2,24 +2,35 @@ use std::fmt;
 
 use super::*;
 
+/// Writes a word as source, which `parse_word` reads back as an equal
+/// word whenever `Word::reads_back` holds.
 impl fmt::Display for Word {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Word::Int(i) => write!(f, "{}", i),
+        match *self {
+            Word::Bool(b) => write!(f, "{}", b),
 
-            &Word::Hex(h) => write!(f, "#{:x}", h),
+            Word::Int(i) => write!(f, "{}", i),
 
-            &Word::Str(ref s) => write!(f, "\"{}\"", s),
+            Word::Hex(h) => write!(f, "#{:x}", h),
 
-            &Word::Atom(ref a) => write!(f, "{}", a),
+            // Debug formatting always keeps a point or an exponent, so
+            // the literal cannot be mistaken for an integer.
+            Word::Float(x) => write!(f, "{:?}", x),
 
-            &Word::List(ref words) => if words.is_empty() {
+            #[cfg(feature = "bigint")]
+            Word::Big(ref b) => write!(f, "{}", b),
+
+            Word::Str(ref s) => write!(f, "{}", Quoted(s)),
+
+            Word::Atom(ref a, _) => write!(f, "{}", a),
+
+            Word::List(ref words) => if words.is_empty() {
                 write!(f, "{{}}")
             } else {
                 write!(f, "{{ {} }}", words.flatten(" "))
             },
 
-            &Word::Dict(ref map) => if map.len() == 0 {
+            Word::Dict(ref map) => if map.is_empty() {
                 write!(f, "dict {{}}")
             } else {
                 write!(f, "dict {{ {} }}", map.flatten("; "))
@@ -28,6 +39,53 @@ impl fmt::Display for Word {
     }
 }
 
+/// A string as a literal the parser reads back unchanged.
+pub(crate) struct Quoted<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+
+        for ch in self.0.chars() {
+            match ch {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\t' => f.write_str("\\t")?,
+                '\r' => f.write_str("\\r")?,
+                '\0' => f.write_str("\\0")?,
+                ch if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32)?,
+                ch => write!(f, "{}", ch)?,
+            }
+        }
+
+        f.write_str("\"")
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in {}", &self.name)?;
+
+        match self.tail {
+            0 => {},
+            1 => write!(f, " (tail)")?,
+            n => write!(f, " (tail, {} times)", n)?,
+        }
+
+        match self.span {
+            Some(ref span) => write!(f, " at {}", span),
+            None => Ok(()),
+        }
+    }
+}
+
 impl fmt::Display for TypeSpec {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut vars = "abcdefghijklmnopqrstuvwxyz".chars();
@@ -45,63 +103,164 @@ impl fmt::Display for TypeSpec {
     }
 }
 
-impl fmt::Display for ParseErr {
+impl fmt::Display for Span {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", match self {
-            &ParseErr::MissingOpenBrace => "missing {",
-            &ParseErr::MissingCloseBrace => "missing }",
-            &ParseErr::MissingEndQuote => "missing \"",
-            &ParseErr::BadHexLiteral => "invalid hex format",
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+impl fmt::Display for ParseErrKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            ParseErrKind::MissingOpenBrace => "missing {",
+            ParseErrKind::MissingCloseBrace => "missing }",
+            ParseErrKind::MissingEndQuote => "missing \"",
+            ParseErrKind::MissingHeredocEnd => "missing end of heredoc",
+            ParseErrKind::BadHexLiteral => "invalid hex format",
+            ParseErrKind::BadEscape => "invalid escape sequence",
+            ParseErrKind::BadHeredoc => "heredoc must start on the next line",
+            ParseErrKind::BadDict => "dict entries must be \"key\" = value",
+            ParseErrKind::NotOneWord => "expected exactly one word",
+            ParseErrKind::IntegerTooLarge => "integer literal out of range",
+            ParseErrKind::FloatTooLarge => "float literal out of range",
         })
     }
 }
 
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", &self.span, &self.kind)?;
+
+        if let Some(ref open) = self.open {
+            excerpt(f, &self.source, open, "unclosed here")
+        } else {
+            excerpt(f, &self.source, &self.span, "")
+        }
+    }
+}
+
+/// Quotes the source line containing `span` and underlines the span.
+fn excerpt(f: &mut fmt::Formatter, source: &str, span: &Span, label: &str)
+    -> fmt::Result
+{
+    let text = source.lines().nth(span.line - 1).unwrap_or("");
+    let gutter = format!("{}", span.line);
+    let blank = " ".repeat(gutter.len());
+
+    // Keep tabs so the caret lines up with the quoted text.
+    let indent: String = text.chars().take(span.col - 1).map(|ch| {
+        if ch == '\t' { '\t' } else { ' ' }
+    }).collect();
+
+    let carets = "^".repeat(span.len.max(1));
+
+    writeln!(f, "{} |", &blank)?;
+    writeln!(f, "{} | {}", &gutter, text)?;
+    write!(f, "{} | {}{}", &blank, &indent, &carets)?;
+
+    if label.is_empty() {
+        Ok(())
+    } else {
+        write!(f, " {}", label)
+    }
+}
+
 impl fmt::Display for EvalErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &EvalErr::StackUnderflow => write!(f, "stack underflow"),
+        match *self {
+            EvalErr::StackUnderflow => write!(f, "stack underflow"),
 
-            &EvalErr::DivideByZero => write!(f, "divided by zero"),
+            EvalErr::DivideByZero => write!(f, "divided by zero"),
 
-            &EvalErr::CantCoerce(ref word, ref typename) => {
+            EvalErr::Overflow => write!(f, "arithmetic overflow"),
+
+            EvalErr::CantCoerce(ref word, ref typename) => {
                 write!(f, "cannot convert {} to {}", word, typename)
             },
 
-            &EvalErr::WrongType(ref word, ref typename) => {
+            EvalErr::WrongType(ref word, ref typename) => {
                 write!(f, "type of {} is not {}", word, typename)
             },
 
-            &EvalErr::CantUnderstand(ref name) => {
+            EvalErr::CantUnderstand(ref name) => {
                 write!(f, "can't understand {}", name)
             },
 
-            &EvalErr::BadParse(ref err) => {
+            EvalErr::BadParse(ref err) => {
                 write!(f, "{}", err)
             },
 
-            &EvalErr::EmptyList => {
+            EvalErr::EmptyList => {
                 write!(f, "empty list")
             },
 
-            &EvalErr::MacroFailed => {
+            EvalErr::MacroFailed => {
                 write!(f, "bad arguments for macro")
             },
 
-            &EvalErr::IllegalStackEffect(input, output) => {
+            EvalErr::IllegalStackEffect(input, output) => {
                 write!(f, "illegal stack effect ( {} -- {} )", input, output)
             },
+
+            EvalErr::At(ref span, ref err) => {
+                write!(f, "{}: {}", span, err)
+            },
+
+            EvalErr::Io(_, ref message) => {
+                write!(f, "{}", message)
+            },
+
+            EvalErr::MissingKey(ref key) => {
+                write!(f, "no such key {}", key)
+            },
+
+            EvalErr::Thrown(ref word) => match *word {
+                Word::Str(ref message) => write!(f, "{}", message),
+
+                Word::Dict(ref dict) => match dict.get("message") {
+                    Some(Word::Str(message)) => write!(f, "{}", message),
+                    _ => write!(f, "uncaught {}", word),
+                },
+
+                ref other => write!(f, "uncaught {}", other),
+            },
+
+            EvalErr::PermissionDenied(ref what) => {
+                write!(f, "permission denied: {}", what)
+            },
+
+            EvalErr::BadImage(ref why) => {
+                write!(f, "bad image: {}", why)
+            },
+
+            EvalErr::LimitExceeded(limit) => {
+                write!(f, "{} limit exceeded", limit)
+            },
         }
     }
 }
 
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Limit::Fuel => "fuel",
+            Limit::DataDepth => "data stack depth",
+            Limit::CodeDepth => "code stack depth",
+            Limit::HeapBytes => "heap size",
+        })
+    }
+}
+
 impl fmt::Display for TypeName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", match self {
-            &TypeName::Atom => "atom",
-            &TypeName::Int => "integer",
-            &TypeName::Hex => "hex",
-            &TypeName::Str => "string",
-            &TypeName::List => "list",
+        write!(f, "{}", match *self {
+            TypeName::Atom => "atom",
+            TypeName::Int => "integer",
+            TypeName::Hex => "hex",
+            TypeName::Float => "float",
+            TypeName::Str => "string",
+            TypeName::List => "list",
+            TypeName::Dict => "dict",
         })
     }
 }
@@ -109,23 +268,22 @@ impl fmt::Display for TypeName {
 impl Word {
     pub fn pretty_print(&self, indent_level: usize) -> Vec<String> {
         let mut lines = vec![];
+        let indent = "    ".repeat(indent_level);
 
-        match self {
-            &Word::List(ref items) => if items.is_empty() {
-                lines.push("{}".to_owned());
+        match *self {
+            Word::List(ref items) => if items.is_empty() {
+                lines.push(format!("{}{{}}", &indent));
             } else {
-                lines.push("{".to_owned());
+                lines.push(format!("{}{{", &indent));
 
                 for item in items.iter().rev() {
-                    for line in item.pretty_print(indent_level + 1) {
-                        lines.push(format!("    {}", line));
-                    }
+                    lines.extend(item.pretty_print(indent_level + 1));
                 }
 
-                lines.push("}".to_owned());
+                lines.push(format!("{}}}", &indent));
             },
 
-            other => lines.push(format!("{}", other)),
+            ref other => lines.push(format!("{}{}", &indent, other)),
         }
 
         lines
@@ -0,0 +1,414 @@
+//! Saving a shell's state to bytes and reading it back.
+//!
+//! An image starts with a magic string and a format version, followed
+//! by the dictionary, the data stack and the code stack. Integers are
+//! little-endian, lengths and counts are `u32`, and strings are UTF-8
+//! preceded by their length in bytes.
+//!
+//! Native words belong to the host, so they are left out; the host
+//! registers them again after loading. Pending `try`s are not saved
+//! either, so an image taken inside one will not recover from errors
+//! raised after it is loaded.
+
+use std::io::{self, Read, Write};
+
+use super::*;
+
+const MAGIC: &[u8; 8] = b"BFIMAGE\0";
+
+/// Bumped whenever the format changes. Images of a later version are
+/// refused rather than misread. Each version only adds to the last, so
+/// earlier images still load.
+pub const VERSION: u32 = 4;
+
+const ATOM: u8 = 0;
+const INT: u8 = 1;
+const HEX: u8 = 2;
+const STR: u8 = 3;
+const LIST: u8 = 4;
+const DICT: u8 = 5;
+const FLOAT: u8 = 6;
+const BIG: u8 = 7;
+const BOOL: u8 = 8;
+
+const PRIMITIVE: u8 = 0;
+const INTERPRETED: u8 = 1;
+
+pub(crate) fn save<W: Write>(shell: &Shell, out: W) -> Result<(), EvalErr> {
+    let mut enc = Encoder {
+        out,
+        builtins: Builtin::default_bindings(),
+    };
+
+    enc.out.write_all(MAGIC)?;
+    enc.u32(VERSION)?;
+
+    let saved: Vec<_> = shell.dict.iter().filter(|&(_, binding)| {
+        !matches!(*binding, Binding::Native(..))
+    }).collect();
+
+    enc.len(saved.len())?;
+    for (name, binding) in saved {
+        enc.str(name.as_str())?;
+        enc.binding(binding)?;
+    }
+
+    enc.len(shell.data.len())?;
+    for word in &shell.data {
+        enc.word(word)?;
+    }
+
+    let code = shell.frames();
+
+    enc.len(code.len())?;
+    for frame in &code {
+        enc.words(&frame.body)?;
+        enc.len(frame.left)?;
+
+        match frame.call {
+            Some(ref call) => {
+                enc.u8(1)?;
+                enc.str(call.name.as_str())?;
+                enc.span(&call.span)?;
+            },
+
+            None => enc.u8(0)?,
+        }
+    }
+
+    enc.out.flush()?;
+    Ok(())
+}
+
+/// What an image holds, ready to be swapped into a shell.
+pub(crate) struct Image {
+    pub dict: OrderMap<Symbol, Binding>,
+    pub data: VecDeque<Word>,
+    pub code: Vec<Frame>,
+}
+
+pub(crate) fn load<R: Read>(input: R) -> Result<Image, EvalErr> {
+    let mut dec = Decoder {
+        input,
+        builtins: Builtin::default_bindings(),
+    };
+
+    let mut magic = [0; 8];
+    dec.bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(bad("not a backforth image"));
+    }
+
+    let version = dec.u32()?;
+    if version == 0 || version > VERSION {
+        return Err(EvalErr::BadImage(format!(
+            "image is version {}, expected at most {}", version, VERSION
+        )));
+    }
+
+    let mut dict = OrderMap::new();
+    for _ in 0 .. dec.u32()? {
+        let name = Symbol::intern(&dec.str()?);
+        let binding = dec.binding()?;
+        dict.insert(name, binding);
+    }
+
+    let data = dec.words()?;
+
+    let mut code = Vec::new();
+    for _ in 0 .. dec.u32()? {
+        let body = Rc::new(dec.words()?);
+
+        let left = dec.u32()? as usize;
+        if left > body.len() {
+            return Err(bad("frame position out of range"));
+        }
+
+        let call = match dec.u8()? {
+            0 => None,
+            1 => Some(Call {
+                name: Symbol::intern(&dec.str()?),
+                span: dec.span()?,
+                tail: 0,
+            }),
+            _ => return Err(bad("invalid frame")),
+        };
+
+        code.push(Frame { body, left, call, code: None, pc: 0, rounds: Vec::new(), tail: None });
+    }
+
+    Ok(Image { dict, data, code })
+}
+
+fn bad(why: &str) -> EvalErr {
+    EvalErr::BadImage(why.to_owned())
+}
+
+struct Encoder<W> {
+    out: W,
+    builtins: OrderMap<Symbol, Binding>,
+}
+
+impl<W: Write> Encoder<W> {
+    fn u8(&mut self, n: u8) -> io::Result<()> {
+        self.out.write_all(&[n])
+    }
+
+    fn u32(&mut self, n: u32) -> io::Result<()> {
+        self.out.write_all(&n.to_le_bytes())
+    }
+
+    fn len(&mut self, n: usize) -> Result<(), EvalErr> {
+        let n = u32::try_from(n).map_err(|_| bad("too large to save"))?;
+        Ok(self.u32(n)?)
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), EvalErr> {
+        self.len(s.len())?;
+        Ok(self.out.write_all(s.as_bytes())?)
+    }
+
+    fn span(&mut self, span: &Option<Rc<Span>>) -> Result<(), EvalErr> {
+        let span = match *span {
+            Some(ref span) => span,
+            None => return Ok(self.u8(0)?),
+        };
+
+        self.u8(1)?;
+        self.str(&span.file)?;
+        self.len(span.line)?;
+        self.len(span.col)?;
+        self.len(span.len)
+    }
+
+    fn words(&mut self, words: &VecDeque<Word>) -> Result<(), EvalErr> {
+        self.len(words.len())?;
+        for word in words {
+            self.word(word)?;
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, word: &Word) -> Result<(), EvalErr> {
+        match *word {
+            Word::Atom(name, ref span) => {
+                self.u8(ATOM)?;
+                self.str(name.as_str())?;
+                self.span(span)
+            },
+
+            Word::Bool(b) => {
+                self.u8(BOOL)?;
+                Ok(self.u8(b as u8)?)
+            },
+
+            Word::Int(i) => {
+                self.u8(INT)?;
+                Ok(self.out.write_all(&i.to_le_bytes())?)
+            },
+
+            Word::Hex(h) => {
+                self.u8(HEX)?;
+                Ok(self.u32(h)?)
+            },
+
+            Word::Float(f) => {
+                self.u8(FLOAT)?;
+                Ok(self.out.write_all(&f.to_le_bytes())?)
+            },
+
+            #[cfg(feature = "bigint")]
+            Word::Big(ref b) => {
+                self.u8(BIG)?;
+                let bytes = b.to_signed_bytes_le();
+                self.len(bytes.len())?;
+                Ok(self.out.write_all(&bytes)?)
+            },
+
+            Word::Str(ref s) => {
+                self.u8(STR)?;
+                self.str(s)
+            },
+
+            Word::List(ref words) => {
+                self.u8(LIST)?;
+                self.words(words)
+            },
+
+            Word::Dict(ref dict) => {
+                self.u8(DICT)?;
+                self.len(dict.len())?;
+                for (key, value) in dict {
+                    self.str(key)?;
+                    self.word(value)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn binding(&mut self, binding: &Binding) -> Result<(), EvalErr> {
+        match *binding {
+            Binding::Primitive(op) => {
+                // Builtins are saved by their usual names, whatever
+                // name the shell binds them under.
+                let name = self.builtins.iter().find(|&(_, binding)| {
+                    matches!(*binding, Binding::Primitive(other) if other == op)
+                }).map(|(name, _)| *name).unwrap();
+
+                self.u8(PRIMITIVE)?;
+                self.str(name.as_str())
+            },
+
+            Binding::Interpreted(spec, ref word) => {
+                self.u8(INTERPRETED)?;
+                self.len(spec.input)?;
+                self.len(spec.output)?;
+                self.u8(spec.exact as u8)?;
+                self.word(word)
+            },
+
+            Binding::Native(..) => unreachable!(),
+        }
+    }
+}
+
+struct Decoder<R> {
+    input: R,
+    builtins: OrderMap<Symbol, Binding>,
+}
+
+impl<R: Read> Decoder<R> {
+    fn bytes(&mut self, buf: &mut [u8]) -> Result<(), EvalErr> {
+        self.input.read_exact(buf).map_err(|err| {
+            match err.kind() {
+                io::ErrorKind::UnexpectedEof => bad("image is truncated"),
+                _ => EvalErr::from(err),
+            }
+        })
+    }
+
+    fn u8(&mut self) -> Result<u8, EvalErr> {
+        let mut buf = [0; 1];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, EvalErr> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads bytes preceded by their count.
+    fn blob(&mut self) -> Result<Vec<u8>, EvalErr> {
+        let len = self.u32()? as usize;
+        let mut buf = Vec::new();
+
+        // Read through `take` rather than allocating `len` bytes up
+        // front, so a corrupt length cannot ask for gigabytes.
+        (&mut self.input).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(bad("image is truncated"));
+        }
+
+        Ok(buf)
+    }
+
+    fn str(&mut self) -> Result<String, EvalErr> {
+        String::from_utf8(self.blob()?).map_err(|_| bad("invalid UTF-8"))
+    }
+
+    fn span(&mut self) -> Result<Option<Rc<Span>>, EvalErr> {
+        match self.u8()? {
+            0 => Ok(None),
+
+            1 => Ok(Some(Rc::new(Span {
+                file: self.str()?.into(),
+                line: self.u32()? as usize,
+                col: self.u32()? as usize,
+                len: self.u32()? as usize,
+            }))),
+
+            _ => Err(bad("invalid span")),
+        }
+    }
+
+    fn words(&mut self) -> Result<VecDeque<Word>, EvalErr> {
+        let mut words = VecDeque::new();
+        for _ in 0 .. self.u32()? {
+            words.push_back(self.word()?);
+        }
+        Ok(words)
+    }
+
+    fn word(&mut self) -> Result<Word, EvalErr> {
+        Ok(match self.u8()? {
+            ATOM => {
+                let name = Symbol::intern(&self.str()?);
+                Word::Atom(name, self.span()?)
+            },
+
+            BOOL => Word::Bool(self.u8()? != 0),
+            INT => Word::Int(self.u32()? as i32),
+            HEX => Word::Hex(self.u32()?),
+
+            FLOAT => {
+                let mut buf = [0; 8];
+                self.bytes(&mut buf)?;
+                Word::Float(f64::from_le_bytes(buf))
+            },
+            BIG => self.big()?,
+            STR => Word::Str(self.str()?),
+            LIST => Word::from(self.words()?),
+
+            DICT => {
+                let mut dict = OrderMap::new();
+                for _ in 0 .. self.u32()? {
+                    let key = self.str()?;
+                    dict.insert(key, self.word()?);
+                }
+                Word::Dict(dict)
+            },
+
+            _ => return Err(bad("invalid word")),
+        })
+    }
+
+    #[cfg(feature = "bigint")]
+    fn big(&mut self) -> Result<Word, EvalErr> {
+        let bytes = self.blob()?;
+        Ok(Word::from(num_bigint::BigInt::from_signed_bytes_le(&bytes)))
+    }
+
+    #[cfg(not(feature = "bigint"))]
+    fn big(&mut self) -> Result<Word, EvalErr> {
+        Err(bad("big integers need the bigint feature"))
+    }
+
+    fn binding(&mut self) -> Result<Binding, EvalErr> {
+        match self.u8()? {
+            PRIMITIVE => {
+                let name = self.str()?;
+                match self.builtins.get(&Symbol::intern(&name)) {
+                    Some(binding) => Ok(binding.clone()),
+                    None => Err(EvalErr::BadImage({
+                        format!("no builtin named {}", name)
+                    })),
+                }
+            },
+
+            INTERPRETED => {
+                let spec = TypeSpec {
+                    input: self.u32()? as usize,
+                    output: self.u32()? as usize,
+                    exact: self.u8()? != 0,
+                };
+
+                Ok(Binding::Interpreted(spec, Rc::new(self.word()?)))
+            },
+
+            _ => Err(bad("invalid binding")),
+        }
+    }
+}
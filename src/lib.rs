@@ -1,25 +1,57 @@
 extern crate ordermap;
+#[cfg(feature = "bigint")]
+extern crate num_bigint;
+#[cfg(feature = "bigint")]
+extern crate num_traits;
 
 mod parser;
 mod display;
-
-use std::collections::{VecDeque};
+mod symbol;
+mod compile;
+mod limits;
+mod sandbox;
+mod native;
+mod builder;
+mod image;
+mod arith;
+mod tail;
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
 
 use ordermap::OrderMap;
 
-use parser::{ParseErr};
+pub use parser::{parse, parse_named, parse_word, ParseErr, ParseErrKind, Span};
+use parser::parse_bare;
+pub use symbol::Symbol;
+pub use limits::{Limit, Limits};
+pub use sandbox::Sandbox;
+pub use native::{NativeFn, Stack};
+pub use builder::ShellBuilder;
 
-pub use parser::parse;
+use arith::{arith, bitwise, Arith, Bitwise};
+use compile::{compile, lowered, Compiled, Op};
+use display::Quoted;
+use native::Native;
+use tail::Tail;
 
-static STDLIB: &'static str = include_str!("stdlib.\\iv");
+static STDLIB: &str = include_str!("stdlib.\\iv");
 
 #[derive(Clone, Debug)]
 pub enum Word {
-    Atom(String),
+    Atom(Symbol, Option<Rc<Span>>),
+    Bool(bool),
     Int(i32),
     Hex(u32),
+    Float(f64),
+    /// An integer too large for `Int`. Never holds one that would fit.
+    #[cfg(feature = "bigint")]
+    Big(num_bigint::BigInt),
     Str(String),
-    List(VecDeque<Word>),
+    List(Rc<VecDeque<Word>>),
     Dict(OrderMap<String, Word>),
 }
 
@@ -28,12 +60,20 @@ pub enum EvalErr {
     StackUnderflow,
     CantUnderstand(String),
     DivideByZero,
+    Overflow,
     CantCoerce(Word, TypeName),
     WrongType(Word, TypeName),
     BadParse(ParseErr),
     EmptyList,
     MacroFailed,
     IllegalStackEffect(usize, usize),
+    At(Rc<Span>, Box<EvalErr>),
+    Io(io::ErrorKind, String),
+    MissingKey(String),
+    Thrown(Word),
+    LimitExceeded(Limit),
+    PermissionDenied(String),
+    BadImage(String),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -41,15 +81,52 @@ pub enum TypeName {
     Atom,
     Int,
     Hex,
+    Float,
     Str,
     List,
+    Dict,
 }
 
 pub struct Shell {
-    dict: OrderMap<String, Binding>,
+    dict: OrderMap<Symbol, Binding>,
     data: VecDeque<Word>,
-    code: Vec<Word>,
-    restore: Vec<Env>,
+    code: Vec<Frame>,
+    restore: Vec<Handler>,
+
+    /// Handlers below this belong to a run that `call` has set aside.
+    /// They still save what they need from the data stack, but nothing
+    /// inside the call may pop them.
+    outer: usize,
+
+    undo: Vec<(Symbol, Option<Binding>)>,
+
+    /// Where in the undo log each name was last recorded. A `try` only
+    /// needs a name's oldest value, so a loop that keeps rebinding the
+    /// same name adds one entry rather than one per iteration.
+    logged: HashMap<Symbol, usize>,
+
+    trace: Vec<Call>,
+    popeh: Rc<VecDeque<Word>>,
+    compiled: bool,
+    cache: HashMap<Symbol, (Rc<VecDeque<Word>>, Rc<Compiled>)>,
+
+    /// Bumped whenever a name bound to a primitive, or one the compiler
+    /// lowers, is rebound, which invalidates every compiled body.
+    epoch: u64,
+
+    /// The most frames the code stack has held at once.
+    peak: usize,
+
+    /// Bytes of atom names first interned while this shell was running.
+    /// They are never freed, so they count towards `heap_bytes`.
+    atoms: usize,
+
+    limits: Limits,
+    fuel: Option<u64>,
+    sandbox: Sandbox,
+    stdout: Box<dyn Write>,
+    stderr: Option<Box<dyn Write>>,
+    stdin: Box<dyn BufRead>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -61,9 +138,12 @@ enum Builtin {
     If,
     Try,
     PopEH,
+    Throw,
+    Rethrow,
     Quote,
     Explode,
     Capture,
+    Backtrace,
     Debug,
     Inspect,
     Len,
@@ -73,6 +153,11 @@ enum Builtin {
     Shift,
     Unshift,
     Parse,
+    ParseAs,
+    Incomplete,
+    Show,
+    Read,
+    ImageSave,
     Echo,
     Prompt,
     Command,
@@ -84,23 +169,42 @@ enum Builtin {
     Clear,
     Strcat,
     Lines,
+    Get,
     Hex,
     Int,
+    Float,
+    Bool,
+    Not,
     OpAdd,
     OpSub,
     OpMul,
     OpDiv,
     OpNeg,
+    OpMod,
+    OpPow,
     OpEql,
     OpLt,
     OpGt,
+    OpLe,
+    OpGe,
+    Abs,
+    Min,
+    Max,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
     InfixExpr,
 }
 
 #[derive(Clone, Debug)]
 enum Binding {
     Primitive(Builtin),
-    Interpreted(TypeSpec, Word),
+    /// Shared, so that looking a word up to call it copies nothing.
+    Interpreted(TypeSpec, Rc<Word>),
+    Native(TypeSpec, Native),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -110,66 +214,402 @@ pub struct TypeSpec {
     pub exact: bool,
 }
 
-struct Env {
-    dict: OrderMap<String, Binding>,
-    data: VecDeque<Word>,
-    code: Vec<Word>,
+/// An interpreted word whose body is being executed.
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub name: Symbol,
+    pub span: Option<Rc<Span>>,
+
+    /// How many times in a row the word called on in tail position, so
+    /// that its frame is gone. Zero while the frame is still there.
+    pub tail: usize,
+}
+
+/// A body being executed, from the end. The body is shared with the
+/// definition it came from; only the count of words left is per-frame.
+/// A compiled frame runs instructions instead, and keeps its place in
+/// them as `pc`.
+#[derive(Clone)]
+struct Frame {
+    body: Rc<VecDeque<Word>>,
+    left: usize,
+    call: Option<Call>,
+    code: Option<Rc<Compiled>>,
+    pc: usize,
+
+    /// How many rounds each loop lowered into the compiled body has
+    /// been through since it last started.
+    rounds: Vec<usize>,
+
+    /// Callers this frame replaced by being called in tail position.
+    tail: Option<Rc<Tail>>,
+}
+
+/// What the next word of a frame asks the shell to do.
+enum Step {
+    Push(Word),
+    Call(Symbol, Option<Rc<Span>>),
+
+    /// Hand over to the VM loop until the compiled frame on top is done
+    /// or something else is.
+    Compiled(Rc<Compiled>),
+}
+
+/// What a pending `try` needs in order to put the shell back the way it
+/// was. Nothing is copied up front: the handler records how much of
+/// each stack it protects, and words are only saved as the body
+/// disturbs them.
+struct Handler {
+    catch: Rc<VecDeque<Word>>,
+
+    /// Number of code frames beneath the body.
+    code: usize,
+
+    /// Height of the data stack that is still untouched.
+    data: usize,
+
+    /// Words popped from below that height, most recent last.
+    saved: Vec<Word>,
+
+    /// Length of the dictionary undo log when the body began.
+    undo: usize,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
 }
 
 impl Shell {
+    /// A shell with every builtin and the standard library. Use a
+    /// `ShellBuilder` for anything else.
     pub fn new() -> Self {
-        let mut shell = Shell {
-            dict: Builtin::default_bindings(),
+        ShellBuilder::new().build().unwrap()
+    }
+
+    fn with_dict(dict: OrderMap<Symbol, Binding>) -> Self {
+        Shell {
+            dict,
             data: VecDeque::new(),
             code: Vec::new(),
             restore: Vec::new(),
-        };
-
-        shell.load(parse(STDLIB).unwrap().into_iter());
-        shell.run().unwrap();
-
-        shell
+            outer: 0,
+            undo: Vec::new(),
+            logged: HashMap::new(),
+            trace: Vec::new(),
+            popeh: Rc::new(vec![Word::atom("popeh")].into()),
+            compiled: false,
+            cache: HashMap::new(),
+            epoch: 0,
+            peak: 0,
+            atoms: 0,
+            limits: Limits::default(),
+            fuel: None,
+            sandbox: Sandbox::new(),
+            stdout: Box::new(io::stdout()),
+            stderr: None,
+            stdin: Box::new(io::BufReader::new(io::stdin())),
+        }
     }
 
     pub fn load<P: Iterator<Item=Word>>(&mut self, program: P) {
-        self.code.extend(program);
+        self.enter(Rc::new(program.collect()), None);
+    }
+
+    /// Chooses between walking definitions word by word and running
+    /// them compiled. Compiled definitions resolve their primitives
+    /// once instead of on every call, and run `if`, `while` and `loop`
+    /// over literal lists as jumps. Either way the stack, the output,
+    /// any errors and their backtraces are the same. Only two things
+    /// differ: fuel is counted per instruction rather than per word, so
+    /// a limited run stops at a different point, and inlined loops take
+    /// up no frames of their own, so they count for less against
+    /// `code_depth` and in `peak_depth`.
+    pub fn set_compiled(&mut self, compiled: bool) {
+        self.compiled = compiled;
     }
 
     pub fn run(&mut self) -> Result<(), EvalErr> {
-        while let Some(word) = self.code.pop() {
-            let name = match word {
-                Word::Atom(name) => name,
+        let mut interned = symbol::interned_bytes();
+
+        while let Some(step) = self.next_step() {
+            if let Step::Compiled(code) = step {
+                self.run_compiled(code, &mut interned)?;
+                continue;
+            }
+
+            if let Err(err) = self.burn() {
+                // Put the word back, so a refuelled run starts with it.
+                if let Some(frame) = self.code.last_mut() {
+                    frame.left += 1;
+                }
 
-                other => {
-                    self.push(other);
-                    continue;
+                return Err(err);
+            }
+
+            match step {
+                Step::Compiled(_) => unreachable!(),
+
+                Step::Push(word) => self.push(word),
+
+                Step::Call(name, span) => {
+                    let def = self.lookup(name);
+                    self.execute(name, span, def)?;
                 },
+            }
+
+            self.settle(&mut interned)?;
+        }
+
+        Ok(())
+    }
+
+    /// The VM loop. Runs the compiled frame on top of the code stack
+    /// until it finishes, or until some other frame needs to run first.
+    fn run_compiled(&mut self, code: Rc<Compiled>, interned: &mut usize)
+        -> Result<(), EvalErr>
+    {
+        let depth = self.code.len();
+
+        loop {
+            if code.epoch != self.epoch {
+                self.unfold();
+                return Ok(());
+            }
+
+            let pc = self.code[depth - 1].pc;
+            let op = match code.ops.get(pc) {
+                Some(op) => op,
+                None => return Ok(()),
             };
 
-            self.lookup(&name).and_then(|def| match def {
-                Binding::Primitive(op) => self.do_builtin(op),
+            self.burn()?;
+            self.code[depth - 1].pc = pc + 1;
 
-                Binding::Interpreted(typespec, word) => {
-                    if self.data.len() < typespec.input {
-                        return Err(EvalErr::StackUnderflow);
+            match *op {
+                Op::Push(ref word) => self.push(word.clone()),
+
+                Op::Prim(prim, name, ref span) => {
+                    let def = Ok(Binding::Primitive(prim));
+                    self.execute(name, span.clone(), def)?;
+                },
+
+                Op::Call(name, ref span) => {
+                    let def = self.lookup(name);
+                    self.execute(name, span.clone(), def)?;
+                },
+
+                Op::If(ref branch) => {
+                    let literal = |word: Option<&Word>, list| matches!(word,
+                        Some(Word::List(items)) if Rc::ptr_eq(items, list));
+
+                    if literal(self.data.get(1), &branch.consequent)
+                        && literal(self.data.get(2), &branch.alternative)
+                    {
+                        let test = self.pop()?.truthy();
+                        self.pop()?;
+                        self.pop()?;
+
+                        self.code[depth - 1].pc = match test {
+                            true => pc + 2,
+                            false => branch.otherwise,
+                        };
+                    } else {
+                        // Not the branches it was compiled for, so let
+                        // `if` itself sort it out.
+                        let def = Ok(Binding::Primitive(Builtin::If));
+                        self.execute(branch.name, branch.span.clone(), def)?;
+                    }
+                },
+
+                Op::Test(exit) => {
+                    if self.data.is_empty() {
+                        // The tree-walker's loop has its own way of
+                        // failing here.
+                        self.code[depth - 1].pc = pc;
+                        self.unfold();
+                        return Ok(());
                     }
 
-                    match word {
-                        Word::List(words) => self.load(words.into_iter()),
-                        other => self.code.push(other),
-                    };
+                    if !self.pop()?.truthy() {
+                        self.code[depth - 1].pc = exit;
+                    }
+                },
+
+                Op::Jump(to) => self.code[depth - 1].pc = to,
+
+                Op::Begin(index, to) => {
+                    let frame = &mut self.code[depth - 1];
+                    frame.rounds[index] = 0;
+                    frame.pc = to;
+                },
 
-                    Ok(())
+                Op::Repeat(index, to) => {
+                    let frame = &mut self.code[depth - 1];
+                    frame.rounds[index] += 1;
+                    frame.pc = to;
+                },
+            }
+
+            self.settle(interned)?;
+
+            let same = self.code.len() == depth && self.code.last().is_some_and(|frame| {
+                frame.code.as_ref().is_some_and(|top| Rc::ptr_eq(top, &code))
+            });
+
+            if !same {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Charges names interned since the last word to the shell, then
+    /// checks the limits.
+    fn settle(&mut self, interned: &mut usize) -> Result<(), EvalErr> {
+        let now = symbol::interned_bytes();
+        self.atoms += now - *interned;
+        *interned = now;
+
+        self.check_limits()
+    }
+
+    /// Runs a word, or hands the error it raises to the innermost `try`.
+    fn execute(
+        &mut self,
+        name: Symbol,
+        span: Option<Rc<Span>>,
+        def: Result<Binding, EvalErr>,
+    ) -> Result<(), EvalErr> {
+        // A rethrown error keeps the location and backtrace it was
+        // first raised with.
+        let rethrow = matches!(def, Ok(Binding::Primitive(Builtin::Rethrow)));
+
+        def.and_then(|def| match def {
+            Binding::Primitive(op) => self.do_builtin(op),
+
+            Binding::Native(typespec, Native(native)) => {
+                if self.data.len() < typespec.input {
+                    return Err(EvalErr::StackUnderflow);
                 }
-            }).or_else(|err| {
-                if let Some(env) = self.restore.pop() {
-                    self.recover(env);
-                    self.push(format!("{} error: {}", &name, &err));
-                    Ok(())
-                } else {
-                    Err(err)
+
+                native(&mut self.stack())
+            },
+
+            Binding::Interpreted(typespec, word) => {
+                if self.data.len() < typespec.input {
+                    return Err(EvalErr::StackUnderflow);
                 }
-            })?;
+
+                let call = Call {
+                    name,
+                    span: span.clone(),
+                    tail: 0,
+                };
+
+                match *word {
+                    Word::List(ref body) => self.invoke(body.clone(), call),
+                    Word::Atom(..) => self.enter(Rc::new(vec![(*word).clone()].into()), call),
+                    ref other => self.push(other.clone()),
+                };
+
+                Ok(())
+            }
+        }).map_err(|err| match span {
+            Some(span) if !rethrow => EvalErr::At(span, Box::new(err)),
+            _ => err,
+        }).or_else(|err| {
+            if !rethrow {
+                self.trace = self.backtrace_here();
+            }
+
+            if let Some(env) = self.pop_handler() {
+                let value = match err.thrown() {
+                    Some(payload) => payload.clone(),
+                    None => self.error_value(name, &err),
+                };
+
+                self.recover(env);
+                self.push(value);
+                Ok(())
+            } else {
+                Err(err)
+            }
+        })
+    }
+
+    /// Sets the limits for everything run from now on, and refills the
+    /// fuel tank.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.fuel = limits.fuel;
+        self.limits = limits;
+    }
+
+    /// Sets what scripts may do outside the shell from now on.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = sandbox;
+    }
+
+    /// Sends the output of `echo` and `prompt` somewhere other than
+    /// the process's standard output.
+    pub fn set_stdout<W: Write + 'static>(&mut self, out: W) {
+        self.stdout = Box::new(out);
+    }
+
+    /// Gives `debug` and `inspect` an output of their own. Until this
+    /// is called they write wherever `echo` does.
+    pub fn set_stderr<W: Write + 'static>(&mut self, err: W) {
+        self.stderr = Some(Box::new(err));
+    }
+
+    fn diagnostics(&mut self) -> &mut dyn Write {
+        match self.stderr {
+            Some(ref mut err) => err,
+            None => &mut self.stdout,
+        }
+    }
+
+    /// Has `prompt` read from somewhere other than the process's
+    /// standard input.
+    pub fn set_stdin<R: BufRead + 'static>(&mut self, input: R) {
+        self.stdin = Box::new(input);
+    }
+
+    /// How many more words may run before the shell runs out of fuel,
+    /// or `None` if there is no fuel limit. A run that stopped for lack
+    /// of fuel picks up where it left off if `set_limits` refuels the
+    /// shell and `run` is called again.
+    pub fn fuel_left(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    fn burn(&mut self) -> Result<(), EvalErr> {
+        match self.fuel {
+            Some(0) => return Err(EvalErr::LimitExceeded(Limit::Fuel)),
+            Some(ref mut fuel) => *fuel -= 1,
+            None => {},
+        }
+
+        Ok(())
+    }
+
+    /// Checked after every word. These are not script errors, so they
+    /// skip any pending `try` and go straight back to the host.
+    fn check_limits(&self) -> Result<(), EvalErr> {
+        let limits = &self.limits;
+
+        if limits.data_depth.is_some_and(|max| self.data.len() > max) {
+            return Err(EvalErr::LimitExceeded(Limit::DataDepth));
+        }
+
+        if limits.code_depth.is_some_and(|max| self.code.len() > max) {
+            return Err(EvalErr::LimitExceeded(Limit::CodeDepth));
+        }
+
+        if let Some(max) = limits.heap_bytes {
+            if limits::heap_size(&self.data) + self.atoms > max {
+                return Err(EvalErr::LimitExceeded(Limit::HeapBytes));
+            }
         }
 
         Ok(())
@@ -179,9 +619,310 @@ impl Shell {
         self.data.clone()
     }
 
-    fn lookup(&self, name: &str) -> Result<Binding, EvalErr> {
-        self.dict.get(name).cloned().ok_or_else(|| {
-            EvalErr::CantUnderstand(name.to_owned())
+    /// Pushes a value onto the data stack.
+    pub fn push<T: Into<Word>>(&mut self, t: T) {
+        self.data.push_front(t.into());
+    }
+
+    /// Takes the top word off the data stack.
+    pub fn pop(&mut self) -> Result<Word, EvalErr> {
+        self.stack().pop()
+    }
+
+    /// Takes the top word off the data stack as a Rust value. A word of
+    /// the wrong type is left where it was.
+    pub fn pop_as<T>(&mut self) -> Result<T, EvalErr>
+        where T: TryFrom<Word, Error = EvalErr>
+    {
+        let word = self.data.front().cloned().ok_or(EvalErr::StackUnderflow)?;
+        let value = T::try_from(word)?;
+        self.pop()?;
+        Ok(value)
+    }
+
+    /// The word `i` places down the data stack, where 0 is the top.
+    pub fn peek(&self, i: usize) -> Option<&Word> {
+        self.data.get(i)
+    }
+
+    /// Runs the named word on its own, as if `name args...` had been
+    /// typed: the first argument ends up on top of the stack. Results
+    /// are left on the stack to be popped.
+    ///
+    /// Anything the shell was in the middle of running is set aside for
+    /// the duration and picked up again afterwards. A `try` it was inside
+    /// still puts back whatever the call takes off the stack, should its
+    /// body go on to fail.
+    pub fn call<I>(&mut self, name: &str, args: I) -> Result<(), EvalErr>
+        where I: IntoIterator<Item = Word>, I::IntoIter: DoubleEndedIterator
+    {
+        for arg in args.into_iter().rev() {
+            self.push(arg);
+        }
+
+        let code = mem::take(&mut self.code);
+        let outer = mem::replace(&mut self.outer, self.restore.len());
+
+        self.enter(Rc::new(vec![Word::atom(name)].into()), None);
+        let result = self.run();
+
+        self.restore.truncate(self.outer);
+        self.outer = outer;
+        self.code = code;
+        result
+    }
+
+    /// Writes the dictionary, the data stack and the code stack out as
+    /// an image, which `load_image` can read back later. Native words
+    /// are left out.
+    pub fn save_image<W: Write>(&self, out: W) -> Result<(), EvalErr> {
+        image::save(self, out)
+    }
+
+    /// Replaces the dictionary, the data stack and the code stack with
+    /// those saved in an image. Everything else about the shell, such
+    /// as its limits and streams, is kept. If the image was saved by a
+    /// running script, `run` picks up where it left off.
+    pub fn load_image<R: io::Read>(&mut self, input: R) -> Result<(), EvalErr> {
+        let image = image::load(input)?;
+
+        self.dict = image.dict;
+        self.data = image.data;
+        self.code = image.code;
+
+        self.restore.clear();
+        self.clear_undo();
+        self.cache.clear();
+        self.trace.clear();
+        self.epoch += 1;
+
+        Ok(())
+    }
+
+    /// The names currently defined, in the order they were first bound.
+    pub fn words(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.dict.keys().cloned()
+    }
+
+    /// Binds a name to a value, exactly as `name = value` would.
+    pub fn define(&mut self, name: &str, value: Word) -> Result<(), EvalErr> {
+        self.assign(Symbol::intern(name), value)
+    }
+
+    /// Removes a name from the dictionary. Returns whether it was there.
+    pub fn undefine(&mut self, name: &str) -> bool {
+        let name = Symbol::intern(name);
+
+        match self.dict.swap_remove(&name) {
+            Some(old) => {
+                self.log(name, Some(old));
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    /// The interpreted words that were executing when the most recent
+    /// error was raised, innermost first. Errors caught by `try` count.
+    ///
+    /// Words that ended by calling another are listed with a nonzero
+    /// `tail`. Repeats of the same word are counted in one entry, and
+    /// only the latest few such words are kept for each frame.
+    pub fn backtrace(&self) -> &[Call] {
+        &self.trace
+    }
+
+    /// The most frames the code stack has held at once. Calls in tail
+    /// position replace the frame they are made from, so this stays
+    /// flat however long a loop runs.
+    pub fn peak_depth(&self) -> usize {
+        self.peak
+    }
+
+    /// Describes an error to a catch block as a dict, so that handlers
+    /// can dispatch on its kind without picking apart the message.
+    fn error_value(&self, name: Symbol, err: &EvalErr) -> Word {
+        let mut dict = OrderMap::new();
+
+        dict.insert("kind".into(), Word::from(err.kind().to_owned()));
+        dict.insert("word".into(), Word::from(name.as_str().to_owned()));
+
+        if let Some(operand) = err.operand() {
+            dict.insert("operand".into(), operand.clone());
+        }
+
+        dict.insert("message".into(), Word::from(err.to_string()));
+
+        if let Some(span) = err.span() {
+            dict.insert("at".into(), Word::from(span.to_string()));
+        }
+
+        dict.insert("trace".into(), Word::from({
+            self.trace.iter().map(|call| {
+                Word::Str(call.to_string())
+            }).collect::<VecDeque<_>>()
+        }));
+
+        Word::Dict(dict)
+    }
+
+    fn backtrace_here(&self) -> Vec<Call> {
+        self.frames().iter().rev().flat_map(|frame| {
+            frame.call.iter().cloned().chain(Tail::calls(&frame.tail))
+        }).collect()
+    }
+
+    /// The code stack as the tree-walker would have it at this point,
+    /// with compiled frames unfolded.
+    fn frames(&self) -> Vec<Frame> {
+        let top = self.code.len().saturating_sub(1);
+
+        self.code.iter().enumerate().flat_map(|(i, frame)| {
+            frame.unfold_running(i == top)
+        }).collect()
+    }
+
+    /// Pushes a body onto the code stack. Frames that have already run
+    /// out of words are discarded first, so a body whose last word
+    /// expands another one does not leave its own frame behind.
+    fn enter<C>(&mut self, body: Rc<VecDeque<Word>>, call: C)
+        where C: Into<Option<Call>>
+    {
+        self.enter_frame(Frame {
+            left: body.len(),
+            body,
+            call: call.into(),
+            code: None,
+            pc: 0,
+            rounds: Vec::new(),
+            tail: None,
+        });
+    }
+
+    /// Enters a list run by `eval`, `if` or `try`. If the list is a
+    /// literal in the compiled body running it, it runs compiled too.
+    fn enter_list(&mut self, body: Rc<VecDeque<Word>>) {
+        let frame = self.list_frame(body);
+        self.enter_frame(frame);
+    }
+
+    fn list_frame(&self, body: Rc<VecDeque<Word>>) -> Frame {
+        let code = self.code.last().and_then(|frame| frame.code.as_ref())
+            .and_then(|code| code.list(&body, &self.dict, self.epoch));
+
+        Frame {
+            left: body.len(),
+            body,
+            call: None,
+            rounds: Frame::rounds(&code),
+            code,
+            pc: 0,
+            tail: None,
+        }
+    }
+
+    /// Enters the body of a definition, compiled if the shell is set to
+    /// run that way.
+    fn invoke(&mut self, body: Rc<VecDeque<Word>>, call: Call) {
+        let code = if self.compiled {
+            Some(self.compiled_body(call.name, &body))
+        } else {
+            None
+        };
+
+        self.enter_frame(Frame {
+            left: body.len(),
+            body,
+            call: Some(call),
+            rounds: Frame::rounds(&code),
+            code,
+            pc: 0,
+            tail: None,
+        });
+    }
+
+    fn enter_frame(&mut self, mut frame: Frame) {
+        let mut tail = None;
+
+        while self.code.last().is_some_and(Frame::done) {
+            let done = self.code.pop().unwrap();
+
+            let replaced = match done.call {
+                Some(call) => Tail::push(done.tail, call),
+                None => done.tail,
+            };
+
+            // Frames further down were replaced before the ones above.
+            tail = match tail {
+                None => replaced,
+                upper => Tail::append(upper, replaced),
+            };
+        }
+
+        frame.tail = tail;
+
+        self.code.push(frame);
+        self.peak = self.peak.max(self.code.len());
+    }
+
+    /// The compiled form of a definition's body, reused for as long as
+    /// the name keeps the same body and no primitive has been rebound.
+    fn compiled_body(&mut self, name: Symbol, body: &Rc<VecDeque<Word>>)
+        -> Rc<Compiled>
+    {
+        if let Some((cached, code)) = self.cache.get(&name) {
+            if Rc::ptr_eq(cached, body) && code.epoch == self.epoch {
+                return code.clone();
+            }
+        }
+
+        let code = Rc::new(compile(&self.dict, body, self.epoch));
+        self.cache.insert(name, (body.clone(), code.clone()));
+        code
+    }
+
+    /// Takes the word after the current one as an argument to a macro.
+    /// A macro inside a `try` body may not reach past the end of it.
+    fn next_arg(&mut self) -> Result<Word, EvalErr> {
+        let floor = match self.restore.last() {
+            Some(handler) if self.restore.len() > self.outer => handler.code + 1,
+            _ => 0,
+        };
+
+        while self.code.len() > floor {
+            if let Some(word) = self.code.last_mut().and_then(Frame::next) {
+                return Ok(word);
+            }
+
+            if self.code.last().is_some_and(Frame::done) {
+                self.code.pop();
+            } else {
+                // The word was lowered into a jump, so go back to words.
+                self.unfold();
+            }
+        }
+
+        Err(EvalErr::MacroFailed)
+    }
+
+    /// Takes the next word to execute, crossing into outer frames as
+    /// inner ones run out.
+    fn next_step(&mut self) -> Option<Step> {
+        while let Some(frame) = self.code.last_mut() {
+            if let Some(step) = frame.step() {
+                return Some(step);
+            }
+
+            self.code.pop();
+        }
+
+        None
+    }
+
+    fn lookup(&self, name: Symbol) -> Result<Binding, EvalErr> {
+        self.dict.get(&name).cloned().ok_or_else(|| {
+            EvalErr::CantUnderstand(name.as_str().to_owned())
         })
     }
 
@@ -206,49 +947,164 @@ impl Shell {
     }
 
     fn get_type(&self, word: &Word) -> Option<TypeSpec> {
-        let name = match word {
-            &Word::Atom(ref name) => name,
+        let name = match *word {
+            Word::Atom(ref name, _) => name,
             _ => return Some(TypeSpec::literal()),
         };
 
-        self.dict.get(name).map(|def| match def {
-            &Binding::Primitive(prim) => prim.get_type(),
-            &Binding::Interpreted(spec, _) => spec,
+        self.dict.get(name).map(|def| match *def {
+            Binding::Primitive(prim) => prim.get_type(),
+            Binding::Interpreted(spec, _) => spec,
+            Binding::Native(spec, _) => spec,
         })
     }
 
-    fn recover(&mut self, env: Env) {
-        self.dict = env.dict;
-        self.code = env.code;
-        self.data = env.data;
+    fn recover(&mut self, handler: Handler) {
+        while self.undo.len() > handler.undo {
+            match self.undo.pop() {
+                Some((name, Some(old))) => {
+                    let new = self.dict.insert(name, old);
+                    self.rebound(name, &new);
+                },
+
+                Some((name, None)) => {
+                    // Undone in reverse order, so this is the newest
+                    // entry and removing it preserves the others' order.
+                    let new = self.dict.swap_remove(&name);
+                    self.rebound(name, &new);
+                },
+
+                None => break,
+            }
+        }
+
+        if self.restore.is_empty() {
+            self.clear_undo();
+        }
+
+        let excess = self.data.len() - handler.data;
+        self.data.drain(.. excess);
+
+        for word in handler.saved.into_iter().rev() {
+            self.data.push_front(word);
+        }
+
+        self.code.truncate(handler.code);
+        self.enter(handler.catch, None);
+    }
+
+    /// The innermost handler, unless it belongs to a run set aside by
+    /// `call`.
+    fn pop_handler(&mut self) -> Option<Handler> {
+        if self.restore.len() > self.outer {
+            self.restore.pop()
+        } else {
+            None
+        }
+    }
+
+    /// See `Stack::touch`.
+    fn touch(&mut self, depth: usize) {
+        self.stack().touch(depth);
+    }
+
+    fn stack(&mut self) -> Stack<'_> {
+        Stack {
+            data: &mut self.data,
+            restore: &mut self.restore,
+        }
+    }
+
+    /// Makes a Rust function available to scripts under `name`,
+    /// replacing whatever the name meant before. `spec` is trusted as
+    /// the function's stack effect when inferring those of words that
+    /// use it.
+    pub fn register<F>(&mut self, name: &str, spec: TypeSpec, f: F)
+        where F: Fn(&mut Stack) -> Result<(), EvalErr> + 'static
+    {
+        let native = Native(Rc::new(f));
+        self.bind(Symbol::intern(name), Binding::Native(spec, native));
+    }
+
+    fn bind(&mut self, name: Symbol, binding: Binding) {
+        let old = self.dict.insert(name, binding);
+        self.log(name, old);
+    }
+
+    /// Binds a name to a value the way `=` does, working out its stack
+    /// effect if it is a list.
+    fn assign(&mut self, name: Symbol, value: Word) -> Result<(), EvalErr> {
+        let typespec = match value {
+            Word::List(ref items) => self.infer_type(items)?,
+            _ => TypeSpec::literal(),
+        };
+
+        self.bind(name, Binding::Interpreted(typespec, Rc::new(value)));
+        Ok(())
+    }
+
+    /// Records what a name meant before it changed, so that a failing
+    /// `try` can put it back.
+    fn log(&mut self, name: Symbol, old: Option<Binding>) {
+        self.rebound(name, &old);
+
+        let floor = match self.restore.last() {
+            Some(handler) => handler.undo,
+            None => return,
+        };
+
+        let logged = self.logged.get(&name).is_some_and(|&i| {
+            i >= floor && self.undo.get(i).is_some_and(|entry| entry.0 == name)
+        });
+
+        if !logged {
+            self.logged.insert(name, self.undo.len());
+            self.undo.push((name, old));
+        }
+    }
+
+    fn clear_undo(&mut self) {
+        self.undo.clear();
+        self.logged.clear();
+    }
+
+    /// Called with whatever a name was bound to before it changed.
+    fn rebound(&mut self, name: Symbol, old: &Option<Binding>) {
+        if matches!(*old, Some(Binding::Primitive(_))) || lowered(name) {
+            self.epoch += 1;
+        }
+    }
+
+    /// Turns the compiled frame on top of the code stack back into the
+    /// frames the tree-walker would have at this point.
+    fn unfold(&mut self) {
+        if let Some(frame) = self.code.pop() {
+            self.code.extend(frame.unfold());
+            self.peak = self.peak.max(self.code.len());
+        }
     }
 
     fn do_builtin(&mut self, builtin: Builtin) -> Result<(), EvalErr> {
         match builtin {
             Builtin::Bye => {
                 self.code.clear();
+                self.restore.truncate(self.outer);
+
+                if self.restore.is_empty() {
+                    self.clear_undo();
+                }
             },
 
             Builtin::Assign => {
-                let name = self.code.pop()
-                    .ok_or(EvalErr::MacroFailed)?
-                    .as_atom()?;
+                let name = self.next_arg()?.as_atom()?;
 
                 let value = self.pop()?;
-
-                let typespec = match &value {
-                    &Word::List(ref items) => self.infer_type(items)?,
-                    _ => TypeSpec::literal(),
-                };
-
-                self.dict.insert(name, {
-                    Binding::Interpreted(typespec, value)
-                });
+                self.assign(name, value)?;
             },
 
             Builtin::Eval => {
                 match self.pop()? {
-                    Word::List(words) => self.load(words.into_iter()),
+                    Word::List(body) => self.enter_list(body),
                     other => self.push(other),
                 }
             },
@@ -258,54 +1114,68 @@ impl Shell {
                 let body = self.pop()?;
 
                 let mut dict = OrderMap::new();
-                for name in names.into_iter() {
-                    dict.insert(name.as_atom()?, self.pop()?);
+                for name in names.iter() {
+                    dict.insert(name.clone().as_atom()?, self.pop()?);
                 }
 
                 self.push(body.expand(&dict));
             },
 
             Builtin::If => {
-                let test = self.pop()?.as_bool()?;
+                let test = self.pop()?.truthy();
                 let consequent = self.pop()?.as_list()?;
                 let alternative = self.pop()?.as_list()?;
 
                 if test {
-                    self.load(consequent.into_iter());
+                    self.enter_list(consequent);
                 } else {
-                    self.load(alternative.into_iter());
+                    self.enter_list(alternative);
                 }
             },
 
             Builtin::Try => {
                 let body = self.pop()?.as_list()?;
                 let catch = self.pop()?.as_list()?;
+                let body = self.list_frame(body);
 
-                let mut restore = Env {
-                    dict: self.dict.clone(),
-                    code: self.code.clone(),
-                    data: self.data.clone(),
-                };
+                let popeh = self.popeh.clone();
+                self.enter(popeh, None);
 
-                restore.code.extend(catch);
-                self.restore.push(restore);
+                self.restore.push(Handler {
+                    catch,
+                    code: self.code.len() - 1,
+                    data: self.data.len(),
+                    saved: Vec::new(),
+                    undo: self.undo.len(),
+                });
 
-                self.code.push(Word::atom("popeh"));
-                self.load(body.into_iter());
+                self.enter_frame(body);
             },
 
             Builtin::PopEH => {
-                self.restore.pop();
+                self.pop_handler();
+
+                if self.restore.is_empty() {
+                    self.clear_undo();
+                }
+            },
+
+            Builtin::Throw | Builtin::Rethrow => {
+                return Err(EvalErr::Thrown(self.pop()?));
             },
 
             Builtin::Quote => {
-                let word = self.code.pop().ok_or(EvalErr::MacroFailed)?;
+                let word = self.next_arg()?;
                 self.push(word);
             },
 
             Builtin::Explode => {
                 let items = self.pop()?.as_list()?;
-                self.data.extend(items.into_iter());
+
+                // These go underneath everything else.
+                let len = self.data.len();
+                self.touch(len);
+                self.data.extend(items.iter().cloned());
             },
 
             Builtin::Capture => {
@@ -313,28 +1183,47 @@ impl Shell {
                 self.push(capture);
             },
 
+            Builtin::Backtrace => {
+                let trace: VecDeque<Word> = self.trace.iter().map(|call| {
+                    Word::Str(call.to_string())
+                }).collect();
+
+                self.push(trace);
+            },
+
             Builtin::Debug => {
-                for word in self.code.iter().rev() {
+                let frames = self.frames();
+                let words = frames.iter().rev().flat_map(|frame| {
+                    frame.body.iter().take(frame.left).rev()
+                });
+
+                let out = self.diagnostics();
+                for word in words {
                     for line in word.pretty_print(0) {
-                        println!("{}", line);
+                        writeln!(out, "{}", line)?;
                     }
                 }
             },
 
             Builtin::Inspect => {
                 let name = self.pop()?.as_atom()?;
-                let def = self.lookup(&name)?;
+                let def = self.lookup(name)?;
 
+                let out = self.diagnostics();
                 match def {
                     Binding::Primitive(prim) => {
                         let spec = prim.get_type();
-                        println!("{} {} = <BUILTIN>", &name, spec);
+                        writeln!(out, "{} {} = <BUILTIN>", &name, spec)?;
+                    },
+
+                    Binding::Native(spec, _) => {
+                        writeln!(out, "{} {} = <NATIVE>", &name, spec)?;
                     },
 
                     Binding::Interpreted(ref spec, ref def) => {
-                        println!("{} {} =", &name, spec);
+                        writeln!(out, "{} {} =", &name, spec)?;
                         for line in def.pretty_print(0) {
-                            println!("{}", line);
+                            writeln!(out, "{}", line)?;
                         }
                     },
                 }
@@ -348,27 +1237,29 @@ impl Shell {
             Builtin::Append => {
                 let mut lhs = self.pop()?.as_list()?;
                 let rhs = self.pop()?.as_list()?;
-                lhs.extend(rhs.into_iter());
+                Rc::make_mut(&mut lhs).extend(rhs.iter().cloned());
                 self.push(lhs);
             },
 
             Builtin::Push => {
                 let value = self.pop()?;
                 let mut list = self.pop()?.as_list()?;
-                list.push_back(value);
+                Rc::make_mut(&mut list).push_back(value);
                 self.push(list);
             },
 
             Builtin::Pop => {
                 let mut list = self.pop()?.as_list()?;
-                let value = list.pop_back().ok_or(EvalErr::EmptyList)?;
+                let value = Rc::make_mut(&mut list).pop_back()
+                    .ok_or(EvalErr::EmptyList)?;
                 self.push(list);
                 self.push(value);
             },
 
             Builtin::Shift => {
                 let mut list = self.pop()?.as_list()?;
-                let value = list.pop_front().ok_or(EvalErr::EmptyList)?;
+                let value = Rc::make_mut(&mut list).pop_front()
+                    .ok_or(EvalErr::EmptyList)?;
                 self.push(list);
                 self.push(value);
             },
@@ -376,30 +1267,74 @@ impl Shell {
             Builtin::Unshift => {
                 let value = self.pop()?;
                 let mut list = self.pop()?.as_list()?;
-                list.push_front(value);
+                Rc::make_mut(&mut list).push_front(value);
                 self.push(list);
             },
 
             Builtin::Parse => {
                 let source = self.pop()?.as_str()?;
-                let program = parse(&source)?;
+                let program = parse_bare(&source)?;
                 self.push(program);
             },
 
+            Builtin::ParseAs => {
+                let name = self.pop()?.as_str()?;
+                let source = self.pop()?.as_str()?;
+                let program = parse_named(&name, &source)?;
+                self.push(program);
+            },
+
+            Builtin::Incomplete => {
+                let source = self.pop()?.as_str()?;
+                let incomplete = match parse_bare(&source) {
+                    Err(err) => err.is_incomplete(),
+                    Ok(_) => false,
+                };
+                self.push(incomplete);
+            },
+
+            Builtin::Show => {
+                let word = self.pop()?;
+                if !word.reads_back() {
+                    return Err(EvalErr::CantCoerce(word, TypeName::Str));
+                }
+
+                self.push(word.to_string());
+            },
+
+            Builtin::Read => {
+                let source = self.pop()?.as_str()?;
+                let word = parse_word(&source)?;
+                self.push(word);
+            },
+
+            Builtin::ImageSave => {
+                let path = self.pop()?.as_str()?;
+                self.sandbox.check_save(&path)?;
+
+                let file = std::fs::File::create(&path)
+                    .map_err(|err| EvalErr::io(&path, err))?;
+                self.save_image(io::BufWriter::new(file))?;
+            },
+
             Builtin::Echo => {
-                println!("{}", self.pop()?.into_string());
+                let text = self.pop()?.into_string();
+                writeln!(self.stdout, "{}", text)?;
             },
 
             Builtin::Prompt => {
-                use std::io::{stdin, stdout, Write};
+                self.sandbox.check_prompt()?;
 
                 let text = self.pop()?.into_string();
-                print!("{}", text);
-                stdout().flush().unwrap();
+                write!(self.stdout, "{}", text)?;
+                self.stdout.flush()?;
 
                 let mut inbuf = String::new();
-                stdin().read_line(&mut inbuf).unwrap();
-                inbuf.pop(); // Discard '\n'
+                self.stdin.read_line(&mut inbuf)?;
+
+                if inbuf.ends_with('\n') {
+                    inbuf.pop();
+                }
 
                 self.push(inbuf);
             },
@@ -408,18 +1343,20 @@ impl Shell {
                 use std::process::Command;
 
                 let name = self.pop()?.into_string();
+                self.sandbox.check_command(&name)?;
+
                 let args = self.pop()?.into_list();
 
                 let mut argv = Vec::with_capacity(args.len());
 
-                for arg in args {
-                    argv.push(arg.as_str()?);
+                for arg in args.iter() {
+                    argv.push(arg.clone().as_str()?);
                 }
 
                 let output = Command::new(&name)
                     .args(argv)
                     .output()
-                    .unwrap();
+                    .map_err(|err| EvalErr::io(&name, err))?;
 
                 self.push({
                     String::from_utf8_lossy(&output.stdout).into_owned()
@@ -431,10 +1368,12 @@ impl Shell {
                 use std::io::Read;
 
                 let path = self.pop()?.as_str()?;
+                self.sandbox.check_load(&path)?;
 
                 let mut inbuf = String::new();
-                let mut file = File::open(&path).unwrap();
-                file.read_to_string(&mut inbuf).unwrap();
+                File::open(&path)
+                    .and_then(|mut file| file.read_to_string(&mut inbuf))
+                    .map_err(|err| EvalErr::io(&path, err))?;
 
                 self.push(inbuf);
             },
@@ -447,7 +1386,7 @@ impl Shell {
 
             Builtin::Pick => {
                 let i = self.pop()?.into_hex()? as usize;
-                let word = self.data.iter().nth(i).cloned()
+                let word = self.data.get(i).cloned()
                     .ok_or(EvalErr::StackUnderflow)?;
                 self.push(word);
             },
@@ -455,6 +1394,7 @@ impl Shell {
             Builtin::Roll => {
                 let i = self.pop()?.into_hex()? as usize;
 
+                self.touch(i + 1);
                 if let Some(word) = self.data.remove(i) {
                     self.push(word)
                 } else {
@@ -467,6 +1407,8 @@ impl Shell {
             },
 
             Builtin::Clear => {
+                let len = self.data.len();
+                self.touch(len);
                 self.data.clear();
             },
 
@@ -488,63 +1430,167 @@ impl Shell {
                 self.push(words);
             },
 
+            Builtin::Get => {
+                let key = self.pop()?.as_str()?;
+                let mut dict = self.pop()?.as_dict()?;
+                let value = dict.swap_remove(&key)
+                    .ok_or(EvalErr::MissingKey(key))?;
+                self.push(value);
+            },
+
+            // Only these two truncate floats, toward zero. Anywhere else a
+            // float is not taken for an integer.
             Builtin::Hex => {
-                let hex = self.pop()?.into_hex()?;
+                let hex = match self.pop()? {
+                    Word::Float(f) if f > -1.0 && f < u32::MAX as f64 + 1.0 => f as u32,
+                    other => other.into_hex()?,
+                };
                 self.push(hex);
             },
 
             Builtin::Int => {
-                let int = self.pop()?.into_int()?;
+                let int = match self.pop()? {
+                    Word::Float(f) if f > i32::MIN as f64 - 1.0 && f < i32::MAX as f64 + 1.0 => {
+                        f as i32
+                    },
+                    other => other.into_int()?,
+                };
                 self.push(int);
             },
 
+            Builtin::Float => {
+                let float = self.pop()?.into_float()?;
+                self.push(float);
+            },
+
+            Builtin::Bool => {
+                let truthy = self.pop()?.truthy();
+                self.push(truthy);
+            },
+
+            Builtin::Not => {
+                let truthy = self.pop()?.truthy();
+                self.push(!truthy);
+            },
+
             Builtin::OpAdd => {
-                self.int_binop(|x, y| Ok(x + y))?;
+                self.arith(Arith::Add)?;
             },
 
             Builtin::OpSub => {
-                self.int_binop(|x, y| Ok(x - y))?;
+                self.arith(Arith::Sub)?;
             },
 
             Builtin::OpMul => {
-                self.int_binop(|x, y| Ok(x * y))?;
+                self.arith(Arith::Mul)?;
             },
 
             Builtin::OpDiv => {
-                self.int_binop(|x, y| x.checked_div(y).ok_or({
-                    EvalErr::DivideByZero
-                }))?;
+                self.arith(Arith::Div)?;
             },
 
             Builtin::OpNeg => {
-                let positive = self.pop()?.as_int()?;
-                self.push(-positive);
+                let word = self.pop()?;
+                self.push(arith::negate(word)?);
+            },
+
+            Builtin::OpMod => {
+                self.arith(Arith::Rem)?;
+            },
+
+            Builtin::OpPow => {
+                self.arith(Arith::Pow)?;
             },
 
             Builtin::OpEql => {
                 let lhs = self.pop()?;
                 let rhs = self.pop()?;
-                self.push(lhs == rhs);
+
+                // Numbers compare by value whatever their kinds, but
+                // other words are only equal to their own kind.
+                let equal = arith::same_value(&lhs, &rhs)
+                    .unwrap_or_else(|| lhs == rhs);
+
+                self.push(equal);
             },
 
             Builtin::OpGt => {
-                self.int_binop(|x, y| Ok(x > y))?;
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                self.push(arith::compare(lhs, rhs)?.is_gt());
             },
 
             Builtin::OpLt => {
-                self.int_binop(|x, y| Ok(x < y))?;
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                self.push(arith::compare(lhs, rhs)?.is_lt());
+            },
+
+            Builtin::OpLe => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                self.push(arith::compare(lhs, rhs)?.is_le());
+            },
+
+            Builtin::OpGe => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                self.push(arith::compare(lhs, rhs)?.is_ge());
+            },
+
+            Builtin::Abs => {
+                let word = self.pop()?;
+                self.push(arith::abs(word)?);
+            },
+
+            // These keep the winner as it was, rather than converting it.
+            Builtin::Min => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                let lesser = arith::compare(lhs.clone(), rhs.clone())?.is_le();
+                self.push(if lesser { lhs } else { rhs });
+            },
+
+            Builtin::Max => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+                let greater = arith::compare(lhs.clone(), rhs.clone())?.is_ge();
+                self.push(if greater { lhs } else { rhs });
+            },
+
+            Builtin::BitAnd => {
+                self.bitwise(Bitwise::And)?;
+            },
+
+            Builtin::BitOr => {
+                self.bitwise(Bitwise::Or)?;
+            },
+
+            Builtin::BitXor => {
+                self.bitwise(Bitwise::Xor)?;
+            },
+
+            Builtin::BitNot => {
+                let word = self.pop()?;
+                self.push(arith::invert(word)?);
+            },
+
+            Builtin::Shl => {
+                self.bitwise(Bitwise::Shl)?;
+            },
+
+            Builtin::Shr => {
+                self.bitwise(Bitwise::Shr)?;
             },
 
             Builtin::InfixExpr => {
-                let rhs = self.code.pop().ok_or(EvalErr::MacroFailed)?;
-                let op = self.code.pop().ok_or(EvalErr::MacroFailed)?;
-                let lhs = self.code.pop().ok_or(EvalErr::MacroFailed)?;
-
-                if let Some(Word::Atom(name)) = self.code.pop() {
-                    if &name == "((" {
-                        self.code.push(op);
-                        self.code.push(lhs);
-                        self.code.push(rhs);
+                let rhs = self.next_arg()?;
+                let op = self.next_arg()?;
+                let lhs = self.next_arg()?;
+
+                if let Ok(Word::Atom(name, _)) = self.next_arg() {
+                    if name == Symbol::intern("((") {
+                        self.enter(Rc::new(vec![op, lhs, rhs].into()), None);
                         return Ok(());
                     }
                 }
@@ -557,30 +1603,25 @@ impl Shell {
         Ok(())
     }
 
-    fn int_binop<R, F>(&mut self, op: F) -> Result<(), EvalErr>
-        where R: Into<Word>, F: FnOnce(i32, i32) -> Result<R, EvalErr>
-    {
-        let lhs = self.pop()?.into_int()?;
-        let rhs = self.pop()?.into_int()?;
-        self.push(op(lhs, rhs)?);
+    fn arith(&mut self, op: Arith) -> Result<(), EvalErr> {
+        let lhs = self.pop()?;
+        let rhs = self.pop()?;
+        self.push(arith(op, lhs, rhs)?);
         Ok(())
     }
 
-    fn push<T: Into<Word>>(&mut self, t: T) {
-        self.data.push_front(t.into());
+    fn bitwise(&mut self, op: Bitwise) -> Result<(), EvalErr> {
+        let lhs = self.pop()?;
+        let rhs = self.pop()?;
+        self.push(bitwise(op, lhs, rhs)?);
+        Ok(())
     }
 
-    fn pop(&mut self) -> Result<Word, EvalErr> {
-        self.data.pop_front().ok_or(EvalErr::StackUnderflow)
-    }
 }
 
 impl From<bool> for Word {
     fn from(b: bool) -> Self {
-        match b {
-            true => Word::Int(1),
-            false => Word::Int(0),
-        }
+        Word::Bool(b)
     }
 }
 
@@ -596,14 +1637,27 @@ impl From<u32> for Word {
     }
 }
 
+impl From<f64> for Word {
+    fn from(f: f64) -> Self {
+        Word::Float(f)
+    }
+}
+
+
 impl From<Vec<Word>> for Word {
     fn from(words: Vec<Word>) -> Self {
-        Word::List(words.into())
+        Word::List(Rc::new(words.into()))
     }
 }
 
 impl From<VecDeque<Word>> for Word {
     fn from(words: VecDeque<Word>) -> Self {
+        Word::List(Rc::new(words))
+    }
+}
+
+impl From<Rc<VecDeque<Word>>> for Word {
+    fn from(words: Rc<VecDeque<Word>>) -> Self {
         Word::List(words)
     }
 }
@@ -620,17 +1674,66 @@ impl From<OrderMap<String, Word>> for Word {
     }
 }
 
+impl TryFrom<Word> for i32 {
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        word.into_int()
+    }
+}
+
+impl TryFrom<Word> for u32 {
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        word.into_hex()
+    }
+}
+
+impl TryFrom<Word> for f64 {
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        word.into_float()
+    }
+}
+
+impl TryFrom<Word> for String {
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        word.as_str()
+    }
+}
+
+impl<T> TryFrom<Word> for Vec<T>
+    where T: TryFrom<Word, Error = EvalErr>
+{
+    type Error = EvalErr;
+
+    fn try_from(word: Word) -> Result<Self, EvalErr> {
+        let items = word.as_list()?;
+        items.iter().cloned().map(T::try_from).collect()
+    }
+}
+
 impl PartialEq for Word {
     fn eq(&self, rhs: &Self) -> bool {
         match (self, rhs) {
-            (&Word::Int(lhs), &Word::Int(rhs)) => lhs == rhs,
-            (&Word::Hex(lhs), &Word::Hex(rhs)) => lhs == rhs,
+            (Word::Bool(lhs), Word::Bool(rhs)) => lhs == rhs,
+            (Word::Int(lhs), Word::Int(rhs)) => lhs == rhs,
+            (Word::Hex(lhs), Word::Hex(rhs)) => lhs == rhs,
+            (Word::Float(lhs), Word::Float(rhs)) => lhs == rhs,
+            #[cfg(feature = "bigint")]
+            (Word::Big(lhs), Word::Big(rhs)) => lhs == rhs,
 
-            (&Word::Atom(ref lhs), &Word::Atom(ref rhs)) => lhs == rhs,
-            (&Word::Str(ref lhs), &Word::Str(ref rhs)) => lhs == rhs,
-            (&Word::List(ref lhs), &Word::List(ref rhs)) => lhs == rhs,
+            (Word::Atom(lhs, _), Word::Atom(rhs, _)) => lhs == rhs,
+            (Word::Str(lhs), Word::Str(rhs)) => lhs == rhs,
+            (Word::List(lhs), Word::List(rhs)) => lhs == rhs,
+
+            (Word::Dict(lhs), Word::Dict(rhs)) => {
+                if lhs.len() != rhs.len() { return false; }
 
-            (&Word::Dict(ref lhs), &Word::Dict(ref rhs)) => {
                 for (k, v) in lhs.iter() {
                     if rhs.get(k) != Some(v) { return false; }
                 }
@@ -643,34 +1746,57 @@ impl PartialEq for Word {
     }
 }
 
+#[allow(clippy::wrong_self_convention)]
 impl Word {
-    fn atom(name: &str) -> Self {
-        Word::Atom(name.to_owned())
+    pub fn atom(name: &str) -> Self {
+        Word::Atom(Symbol::intern(name), None)
     }
 
-    fn as_atom(self) -> Result<String, EvalErr> {
-        match self {
-            Word::Atom(name) => Ok(name),
-            val => Err(EvalErr::WrongType(val, TypeName::Atom)),
+    /// Whether `parse_word` gives this word back from its `Display` text.
+    /// Most do, but there is no way to write an atom spelled like a
+    /// literal or holding a space, nor a float that is NaN or infinite.
+    pub fn reads_back(&self) -> bool {
+        match *self {
+            Word::Float(x) => x.is_finite(),
+
+            Word::Atom(name, _) => match parse_word(name.as_str()) {
+                Ok(Word::Atom(read, _)) => read == name,
+                _ => false,
+            },
+
+            Word::List(ref words) => words.iter().all(Word::reads_back),
+            Word::Dict(ref map) => map.values().all(Word::reads_back),
+            _ => true,
         }
     }
 
-    fn as_bool(self) -> Result<bool, EvalErr> {
+    fn as_atom(self) -> Result<Symbol, EvalErr> {
         match self {
-            Word::Int(0) => Ok(false),
-            Word::Int(_) => Ok(true),
-            val => Err(EvalErr::WrongType(val, TypeName::Int)),
+            Word::Atom(name, _) => Ok(name),
+            val => Err(EvalErr::WrongType(val, TypeName::Atom)),
         }
     }
 
-    fn as_int(self) -> Result<i32, EvalErr> {
-        match self {
-            Word::Int(i) => Ok(i),
-            val => Err(EvalErr::WrongType(val, TypeName::Int)),
+    /// Whether `if` takes this word as true. `false`, zero of any kind
+    /// and anything empty are false. Everything else, atoms included, is
+    /// true.
+    pub fn truthy(&self) -> bool {
+        match *self {
+            Word::Bool(b) => b,
+            Word::Int(i) => i != 0,
+            Word::Hex(h) => h != 0,
+            Word::Float(f) => f != 0.0,
+            Word::Str(ref s) => !s.is_empty(),
+            Word::List(ref words) => !words.is_empty(),
+            Word::Dict(ref dict) => !dict.is_empty(),
+            // Never zero, because a big integer never fits an `Int`.
+            #[cfg(feature = "bigint")]
+            Word::Big(_) => true,
+            Word::Atom(..) => true,
         }
     }
 
-    fn as_list(self) -> Result<VecDeque<Word>, EvalErr> {
+    fn as_list(self) -> Result<Rc<VecDeque<Word>>, EvalErr> {
         match self {
             Word::List(words) => Ok(words),
             val => Err(EvalErr::WrongType(val, TypeName::List)),
@@ -684,10 +1810,17 @@ impl Word {
         }
     }
 
+    fn as_dict(self) -> Result<OrderMap<String, Word>, EvalErr> {
+        match self {
+            Word::Dict(dict) => Ok(dict),
+            val => Err(EvalErr::WrongType(val, TypeName::Dict)),
+        }
+    }
+
     fn into_int(self) -> Result<i32, EvalErr> {
         match self {
             Word::Int(i) => Ok(i),
-            Word::Hex(h) if h <= i32::max_value() as u32 => Ok(h as i32),
+            Word::Hex(h) if h <= i32::MAX as u32 => Ok(h as i32),
             other => Err(EvalErr::CantCoerce(other, TypeName::Int)),
         }
     }
@@ -696,10 +1829,26 @@ impl Word {
         match self {
             Word::Hex(h) => Ok(h),
             Word::Int(i) if i >= 0 => Ok(i as u32),
+            #[cfg(feature = "bigint")]
+            Word::Big(b) => match arith::big_to_hex(&b) {
+                Some(h) => Ok(h),
+                None => Err(EvalErr::CantCoerce(Word::Big(b), TypeName::Hex)),
+            },
             other => Err(EvalErr::CantCoerce(other, TypeName::Hex)),
         }
     }
 
+    fn into_float(self) -> Result<f64, EvalErr> {
+        match self {
+            Word::Float(f) => Ok(f),
+            Word::Int(i) => Ok(i as f64),
+            Word::Hex(h) => Ok(h as f64),
+            #[cfg(feature = "bigint")]
+            Word::Big(ref b) => arith::big_to_float(b),
+            other => Err(EvalErr::CantCoerce(other, TypeName::Float)),
+        }
+    }
+
     fn into_string(self) -> String {
         match self {
             Word::Str(s) => s,
@@ -707,26 +1856,26 @@ impl Word {
         }
     }
 
-    fn into_list(self) -> VecDeque<Word> {
+    fn into_list(self) -> Rc<VecDeque<Word>> {
         match self {
             Word::List(list) => list,
-            other => vec![other].into(),
+            other => Rc::new(vec![other].into()),
         }
     }
 
-    fn expand(self, dict: &OrderMap<String, Word>) -> Self {
+    fn expand(self, dict: &OrderMap<Symbol, Word>) -> Self {
         match self {
-            Word::Atom(name) => if dict.contains_key(&name) {
+            Word::Atom(name, span) => if dict.contains_key(&name) {
                 dict.get(&name).unwrap().clone()
             } else {
-                Word::Atom(name)
+                Word::Atom(name, span)
             },
 
-            Word::List(words) => Word::List({
-                words.into_iter().map(|word| {
-                    word.expand(dict)
+            Word::List(words) => Word::List(Rc::new({
+                words.iter().map(|word| {
+                    word.clone().expand(dict)
                 }).collect()
-            }),
+            })),
 
             other => other,
         }
@@ -739,8 +1888,72 @@ impl From<ParseErr> for EvalErr {
     }
 }
 
+impl From<io::Error> for EvalErr {
+    fn from(err: io::Error) -> Self {
+        EvalErr::Io(err.kind(), err.to_string())
+    }
+}
+
+impl EvalErr {
+    /// A short name for this kind of error, as seen by catch blocks.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            EvalErr::StackUnderflow => "stack-underflow",
+            EvalErr::CantUnderstand(_) => "cant-understand",
+            EvalErr::DivideByZero => "divide-by-zero",
+            EvalErr::Overflow => "overflow",
+            EvalErr::CantCoerce(..) => "cant-coerce",
+            EvalErr::WrongType(..) => "wrong-type",
+            EvalErr::BadParse(_) => "bad-parse",
+            EvalErr::EmptyList => "empty-list",
+            EvalErr::MacroFailed => "macro-failed",
+            EvalErr::IllegalStackEffect(..) => "illegal-stack-effect",
+            EvalErr::At(_, ref err) => err.kind(),
+            EvalErr::Io(..) => "io",
+            EvalErr::MissingKey(_) => "missing-key",
+            EvalErr::Thrown(_) => "thrown",
+            EvalErr::LimitExceeded(_) => "limit-exceeded",
+            EvalErr::PermissionDenied(_) => "permission-denied",
+            EvalErr::BadImage(_) => "bad-image",
+        }
+    }
+
+    /// The payload of an error raised with `throw`.
+    pub fn thrown(&self) -> Option<&Word> {
+        match *self {
+            EvalErr::Thrown(ref word) => Some(word),
+            EvalErr::At(_, ref err) => err.thrown(),
+            _ => None,
+        }
+    }
+
+    /// The value that caused the error, if it was a bad argument.
+    pub fn operand(&self) -> Option<&Word> {
+        match *self {
+            EvalErr::CantCoerce(ref word, _) => Some(word),
+            EvalErr::WrongType(ref word, _) => Some(word),
+            EvalErr::At(_, ref err) => err.operand(),
+            _ => None,
+        }
+    }
+
+    /// Where in the source the error was raised, if known.
+    pub fn span(&self) -> Option<&Span> {
+        match *self {
+            EvalErr::At(ref span, _) => Some(span),
+            EvalErr::BadParse(ref err) => Some(&err.span),
+            _ => None,
+        }
+    }
+
+    /// An I/O error on the named file or program.
+    fn io(name: &str, err: io::Error) -> Self {
+        EvalErr::Io(err.kind(), format!("{}: {}", name, err))
+    }
+}
+
 pub trait Flattenable {
-    fn flatten(&self, &str) -> String;
+    fn flatten(&self, sep: &str) -> String;
 }
 
 impl Flattenable for [Word] {
@@ -761,12 +1974,95 @@ impl Flattenable for VecDeque<Word> {
 
 impl Flattenable for OrderMap<String, Word> {
     fn flatten(&self, sep: &str) -> String {
-        self.iter().map(|(ref k, ref v)| {
-            format!("{} = {}", k, v)
+        self.iter().map(|(k, v)| {
+            format!("{} = {}", Quoted(k), v)
         }).collect::<Vec<_>>().join(sep)
     }
 }
 
+impl Frame {
+    /// Takes the next word, for a macro. A compiled frame only has one
+    /// if its next instruction stands for a single word.
+    fn next(&mut self) -> Option<Word> {
+        if let Some(ref code) = self.code {
+            let word = match code.ops.get(self.pc)? {
+                Op::Push(word) => word.clone(),
+                Op::Prim(_, name, span) | Op::Call(name, span) => {
+                    Word::Atom(*name, span.clone())
+                },
+                _ => return None,
+            };
+
+            self.pc += 1;
+            return Some(word);
+        }
+
+        if self.left == 0 {
+            return None;
+        }
+
+        self.left -= 1;
+        Some(self.body[self.left].clone())
+    }
+
+    /// Like `next`, but says what to do with the word. A compiled frame
+    /// leaves that to the VM loop.
+    fn step(&mut self) -> Option<Step> {
+        if let Some(ref code) = self.code {
+            if code.done(self.pc) {
+                return None;
+            }
+
+            return Some(Step::Compiled(code.clone()));
+        }
+
+        Some(match self.next()? {
+            Word::Atom(name, span) => Step::Call(name, span),
+            other => Step::Push(other),
+        })
+    }
+
+    fn done(&self) -> bool {
+        match self.code {
+            Some(ref code) => code.done(self.pc),
+            None => self.left == 0,
+        }
+    }
+
+    /// The frames the tree-walker would have in place of this one, to
+    /// carry on from. Only a compiled frame turns into anything but
+    /// itself.
+    fn unfold(&self) -> Vec<Frame> {
+        match self.code {
+            Some(ref code) => code.unfold(self, false),
+            None => vec![self.clone()],
+        }
+    }
+
+    /// Like `unfold`, but as the frames would be while the instruction
+    /// this frame is on runs. Unless the frame is on top, that started
+    /// another frame, and the tree-walker would have dropped the one it
+    /// came from if nothing was left of it.
+    fn unfold_running(&self, top: bool) -> Vec<Frame> {
+        let code = match self.code {
+            Some(ref code) => code,
+            None => return vec![self.clone()],
+        };
+
+        let mut frames = code.unfold(self, true);
+
+        if !top && frames.last().is_some_and(|frame| frame.left == 0) {
+            frames.pop();
+        }
+
+        frames
+    }
+
+    fn rounds(code: &Option<Rc<Compiled>>) -> Vec<usize> {
+        code.as_ref().map_or_else(Vec::new, |code| vec![0; code.loops])
+    }
+}
+
 impl From<Builtin> for Binding {
     fn from(op: Builtin) -> Self {
         Binding::Primitive(op)
@@ -797,9 +2093,12 @@ impl Builtin {
             If => inexact(3),
             Try => inexact(2),
             PopEH => exact(0, 0),
+            Throw => exact(1, 0),
+            Rethrow => exact(1, 0),
             Quote => inexact(0),
             Explode => inexact(1),
             Capture => inexact(0),
+            Backtrace => exact(0, 1),
             Debug => exact(0, 0),
             Inspect => exact(1, 0),
             Len => exact(1, 1),
@@ -810,6 +2109,11 @@ impl Builtin {
             Shift => exact(1, 2),
             Unshift => exact(2, 1),
             Parse => exact(1, 1),
+            ParseAs => exact(2, 1),
+            Incomplete => exact(1, 1),
+            Show => exact(1, 1),
+            Read => exact(1, 1),
+            ImageSave => exact(1, 0),
             Echo => exact(1, 0),
             Prompt => exact(1, 1),
             Command => exact(2, 1),
@@ -820,16 +2124,33 @@ impl Builtin {
             Drop => exact(1, 0),
             Clear => inexact(0),
             Lines => exact(1, 1),
+            Get => exact(2, 1),
             Hex => exact(1, 1),
+            Float => exact(1, 1),
+            Bool => exact(1, 1),
+            Not => exact(1, 1),
             Int => exact(1, 1),
             OpAdd => exact(2, 1),
             OpDiv => exact(2, 1),
             OpSub => exact(2, 1),
             OpMul => exact(2, 1),
             OpNeg => exact(1, 1),
+            OpMod => exact(2, 1),
+            OpPow => exact(2, 1),
             OpEql => exact(2, 1),
             OpGt => exact(2, 1),
             OpLt => exact(2, 1),
+            OpLe => exact(2, 1),
+            OpGe => exact(2, 1),
+            Abs => exact(1, 1),
+            Min => exact(2, 1),
+            Max => exact(2, 1),
+            BitAnd => exact(2, 1),
+            BitOr => exact(2, 1),
+            BitXor => exact(2, 1),
+            BitNot => exact(1, 1),
+            Shl => exact(2, 1),
+            Shr => exact(2, 1),
             InfixExpr => inexact(0),
         }
     }
@@ -868,7 +2189,7 @@ macro_rules! order_map {
 }
 
 impl Builtin {
-    fn default_bindings() -> OrderMap<String, Binding> {
+    fn default_bindings() -> OrderMap<Symbol, Binding> {
         use Builtin::*;
 
         order_map![
@@ -879,9 +2200,12 @@ impl Builtin {
             "if" => If,
             "try" => Try,
             "popeh" => PopEH,
+            "throw" => Throw,
+            "rethrow" => Rethrow,
             "quote" => Quote,
             "explode" => Explode,
             "capture" => Capture,
+            "backtrace" => Backtrace,
             "debug" => Debug,
             "inspect" => Inspect,
             "len" => Len,
@@ -891,6 +2215,11 @@ impl Builtin {
             "shift" => Shift,
             "unshift" => Unshift,
             "parse" => Parse,
+            "parse-as" => ParseAs,
+            "incomplete" => Incomplete,
+            "show" => Show,
+            "read" => Read,
+            "image-save" => ImageSave,
             "echo" => Echo,
             "prompt" => Prompt,
             "command" => Command,
@@ -902,17 +2231,55 @@ impl Builtin {
             "clear" => Clear,
             "strcat" => Strcat,
             "lines" => Lines,
+            "get" => Get,
             "hex" => Hex,
             "int" => Int,
+            "float" => Float,
+            "bool" => Bool,
+            "not" => Not,
             "+" => OpAdd,
             "-" => OpSub,
             "*" => OpMul,
             "/" => OpDiv,
             "~" => OpNeg,
+            "%" => OpMod,
+            "pow" => OpPow,
             "==" => OpEql,
             "<" => OpLt,
             ">" => OpGt,
+            "<=" => OpLe,
+            ">=" => OpGe,
+            "abs" => Abs,
+            "min" => Min,
+            "max" => Max,
+            "&" => BitAnd,
+            "|" => BitOr,
+            "^" => BitXor,
+            "invert" => BitNot,
+            "<<" => Shl,
+            ">>" => Shr,
             "))" => InfixExpr,
         ]
     }
 }
+
+#[test]
+fn rebinding_in_a_loop_logs_once() {
+    let mut shell = Shell::new();
+    shell.load(parse("n = 1000").unwrap().into_iter());
+    shell.run().unwrap();
+
+    shell.restore.push(Handler {
+        catch: Rc::default(),
+        code: 0,
+        data: 0,
+        saved: Vec::new(),
+        undo: 0,
+    });
+
+    let source = "while { < 0 n } { n = + -1 n }";
+    shell.load(parse(source).unwrap().into_iter());
+    shell.run().unwrap();
+
+    assert_eq!(shell.undo.len(), 1);
+}
@@ -0,0 +1,62 @@
+use std::collections::{HashSet, VecDeque};
+use std::mem::size_of;
+
+use super::Word;
+
+/// Bounds on the resources a script may use. `None` leaves a resource
+/// unbounded, which is the default for all of them.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Limits {
+    /// How many words may be run, counting literals.
+    pub fuel: Option<u64>,
+
+    /// How many words the data stack may hold.
+    pub data_depth: Option<usize>,
+
+    /// How many bodies may be executing at once.
+    pub code_depth: Option<usize>,
+
+    /// How many bytes the strings, lists and dicts on the data stack
+    /// may take up between them, together with the names of atoms the
+    /// script made up, which are never freed. Checking this walks the
+    /// whole stack after every word, so it is meant for small untrusted
+    /// scripts.
+    pub heap_bytes: Option<usize>,
+}
+
+/// The limit that stopped a script.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Limit {
+    Fuel,
+    DataDepth,
+    CodeDepth,
+    HeapBytes,
+}
+
+/// Roughly how many bytes the words on a stack hold. A list shared
+/// between several words is only counted once.
+pub(crate) fn heap_size(data: &VecDeque<Word>) -> usize {
+    let mut seen = HashSet::new();
+    data.iter().map(|word| size(word, &mut seen)).sum()
+}
+
+fn size(word: &Word, seen: &mut HashSet<*const VecDeque<Word>>) -> usize {
+    match *word {
+        Word::Str(ref s) => s.len(),
+
+        #[cfg(feature = "bigint")]
+        Word::Big(ref b) => b.bits() as usize / 8,
+
+        Word::List(ref items) if seen.insert(&**items) => {
+            items.iter().map(|item| {
+                size_of::<Word>() + size(item, seen)
+            }).sum()
+        },
+
+        Word::Dict(ref dict) => dict.iter().map(|(k, v)| {
+            k.len() + size_of::<Word>() + size(v, seen)
+        }).sum(),
+
+        _ => 0,
+    }
+}
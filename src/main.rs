@@ -1,21 +1,67 @@
 extern crate backforth;
 
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
 fn main() {
-    use backforth::Word;
+    use backforth::{EvalErr, Shell, ShellBuilder, Word};
+
+    let mut args = std::env::args().skip(1);
+    let mut image = None;
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--image" {
+            image = args.next();
+        } else {
+            path = Some(arg);
+        }
+    }
 
-    let mut program = vec![Word::Atom("repl".to_owned())];
+    let mut program = vec![Word::atom("repl")];
 
-    if let Some(path) = std::env::args().nth(1) {
+    if let Some(path) = path {
         program.clear();
-        program.push(Word::Atom("interpret".to_owned()));
+        program.push(Word::atom("interpret"));
         program.push(Word::from(path));
     }
 
-    let mut shell = backforth::Shell::new();
+    let report = |shell: &Shell, err: EvalErr| {
+        println!("{}", err);
+
+        for call in shell.backtrace() {
+            println!("    {}", call);
+        }
+    };
+
+    let mut shell = match image {
+        Some(image) => {
+            // The image brings its own dictionary, stdlib included.
+            let mut shell = ShellBuilder::new().stdlib(false).build().unwrap();
+
+            let loaded = File::open(&image)
+                .map_err(EvalErr::from)
+                .and_then(|file| shell.load_image(BufReader::new(file)));
+
+            if let Err(err) = loaded {
+                println!("{}: {}", image, err);
+                exit(1);
+            }
+
+            // Finish whatever the script that saved the image was doing.
+            if let Err(err) = shell.run() {
+                report(&shell, err);
+                exit(1);
+            }
+
+            shell
+        },
+
+        None => Shell::new(),
+    };
 
     shell.load(program.into_iter());
 
-    shell.run().unwrap_or_else(|err| {
-        println!("{}", err);
-    });
+    shell.run().unwrap_or_else(|err| report(&shell, err));
 }
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+use super::{EvalErr, Handler, Word};
+
+/// The data stack, as handed to a native word. Index 0 is the top.
+///
+/// Words taken off the stack here are still put back if an enclosing
+/// `try` fails, just as they are for the built-in primitives.
+pub struct Stack<'a> {
+    pub(crate) data: &'a mut VecDeque<Word>,
+    pub(crate) restore: &'a mut Vec<Handler>,
+}
+
+impl<'a> Stack<'a> {
+    pub fn push<T: Into<Word>>(&mut self, t: T) {
+        self.data.push_front(t.into());
+    }
+
+    pub fn pop(&mut self) -> Result<Word, EvalErr> {
+        self.touch(1);
+        self.data.pop_front().ok_or(EvalErr::StackUnderflow)
+    }
+
+    pub fn peek(&self, i: usize) -> Option<&Word> {
+        self.data.get(i)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Called before the top `depth` words of the data stack are
+    /// disturbed. Saves any of them that a pending `try` would need to
+    /// put back, and lowers its watermark to match.
+    pub(crate) fn touch(&mut self, depth: usize) {
+        let len = self.data.len();
+        let floor = len - depth.min(len);
+
+        for handler in self.restore.iter_mut().rev() {
+            if handler.data <= floor {
+                break;
+            }
+
+            let saved = self.data.range(len - handler.data .. len - floor);
+            handler.saved.extend(saved.cloned());
+            handler.data = floor;
+        }
+    }
+}
+
+pub type NativeFn = dyn Fn(&mut Stack) -> Result<(), EvalErr>;
+
+/// A word implemented by the host.
+#[derive(Clone)]
+pub(crate) struct Native(pub Rc<NativeFn>);
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<NATIVE>")
+    }
+}
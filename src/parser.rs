@@ -1,40 +1,161 @@
-use super::Word;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
+
+use ordermap::OrderMap;
+
+use super::{Symbol, Word};
 
 pub type Program = Vec<Word>;
 
-#[derive(Copy, Clone, Debug)]
-pub enum ParseErr {
+/// A run of characters in a named source. Lines and columns count from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParseErr {
+    pub kind: ParseErrKind,
+    pub span: Span,
+    pub open: Option<Span>,
+    pub(crate) source: Rc<str>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseErrKind {
     MissingOpenBrace,
     MissingCloseBrace,
     MissingEndQuote,
+    MissingHeredocEnd,
     BadHexLiteral,
+    BadEscape,
+    BadHeredoc,
+    BadDict,
+    NotOneWord,
+    IntegerTooLarge,
+    FloatTooLarge,
+}
+
+impl ParseErr {
+    /// Whether the source was cut short rather than wrong, so that more
+    /// of it could still parse. The REPL reads another line when it is.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind,
+            ParseErrKind::MissingCloseBrace |
+            ParseErrKind::MissingEndQuote |
+            ParseErrKind::MissingHeredocEnd)
+    }
 }
 
 pub fn parse(input: &str) -> Result<Program, ParseErr> {
-    let mut stream = input.chars().peekable();
+    parse_named("<input>", input)
+}
+
+/// Parses source holding exactly one word, such as the text `Display`
+/// gives for a word. Being data rather than code, its atoms get no spans.
+pub fn parse_word(input: &str) -> Result<Word, ParseErr> {
+    let mut program = parse_bare(input)?;
+
+    if program.len() == 1 {
+        Ok(program.remove(0))
+    } else {
+        Err(ParseErr {
+            kind: ParseErrKind::NotOneWord,
+            span: Cursor::new("<input>", input).here(),
+            open: None,
+            source: input.into(),
+        })
+    }
+}
+
+pub fn parse_named(file: &str, input: &str) -> Result<Program, ParseErr> {
+    parse_source(file, input, true)
+}
+
+/// Parses without attaching spans to atoms, for code built at runtime,
+/// which has no file to point into. Parse errors still give positions.
+pub(crate) fn parse_bare(input: &str) -> Result<Program, ParseErr> {
+    parse_source("<input>", input, false)
+}
+
+fn parse_source(file: &str, input: &str, spans: bool) -> Result<Program, ParseErr> {
+    let mut stream = Cursor::new(file, input);
     let mut stack = Stack::with_capacity(8);
-    stack.push();
+    stack.push(None, false);
+
+    let fail = |kind, span, open| Err(ParseErr {
+        kind,
+        span,
+        open,
+        source: input.into(),
+    });
 
     while let Some(ch) = stream.next() {
         match ch {
-            '{' => stack.push(),
+            '{' => stack.push(Some(stream.behind(1)), false),
 
-            '}' => stack.pop()?,
+            '}' => if stack.0.len() > 1 {
+                if let Err(open) = stack.pop() {
+                    return fail(ParseErrKind::BadDict, {
+                        open.unwrap_or_else(|| stream.behind(1))
+                    }, None);
+                }
+            } else {
+                return fail(ParseErrKind::MissingOpenBrace, {
+                    stream.behind(1)
+                }, None);
+            },
 
             '"' => {
+                let open = stream.behind(1);
                 let mut buf = String::new();
                 loop {
                     match stream.next() {
-                        None => return Err(ParseErr::MissingEndQuote),
+                        None => return fail(ParseErrKind::MissingEndQuote, {
+                            stream.here()
+                        }, Some(open)),
                         Some('"') => break,
+                        Some('\\') => match stream.escape() {
+                            Ok(ch) => buf.push(ch),
+                            Err(span) => return fail(ParseErrKind::BadEscape, {
+                                span
+                            }, None),
+                        },
+                        Some(ch) => buf.push(ch),
+                    }
+                }
+                stack.emit(Word::Str(buf));
+            },
+
+            'r' if stream.raw_hashes().is_some() => {
+                let hashes = stream.raw_hashes().unwrap_or(0);
+                for _ in 0 ..= hashes {
+                    stream.next();
+                }
+
+                let open = stream.behind(hashes + 2);
+                let close = format!("\"{}", "#".repeat(hashes));
+
+                let mut buf = String::new();
+                while !buf.ends_with(&close) {
+                    match stream.next() {
+                        None => return fail(ParseErrKind::MissingEndQuote, {
+                            stream.here()
+                        }, Some(open)),
                         Some(ch) => buf.push(ch),
                     }
                 }
-                stack.emit(Word::Str(buf))?;
+
+                buf.truncate(buf.len() - close.len());
+                stack.emit(Word::Str(buf));
             },
 
             ';' | '\n' => {
-                stack.newline()?;
+                stack.newline();
             },
 
             s if s.is_whitespace() => continue,
@@ -52,36 +173,261 @@ pub fn parse(input: &str) -> Result<Program, ParseErr> {
                     word.extend(stream.next());
                 }
 
-                if &word == "#" || word.starts_with("#!") {
+                let span = stream.behind(word.chars().count());
+
+                if word == "#" || word.starts_with("#!") {
                     loop {
                         match stream.next() {
                             Some('\n') | None => break,
                             _ => continue,
                         }
                     }
-                } else if word.starts_with('#') {
-                    word.drain(0 .. 1);
-                    stack.emit(Word::Hex(parse_hex(word)?))?;
+                } else if let Some(digits) = word.strip_prefix('#') {
+                    match parse_hex(digits) {
+                        Some(hex) => stack.emit(Word::Hex(hex)),
+                        None => return fail(ParseErrKind::BadHexLiteral, {
+                            span
+                        }, None),
+                    }
+                } else if word == "true" || word == "false" {
+                    stack.emit(Word::Bool(word == "true"));
                 } else if let Ok(int) = word.parse::<i32>() {
-                    stack.emit(Word::Int(int))?;
+                    stack.emit(Word::Int(int));
+                } else if let Some(big) = parse_big(&word) {
+                    stack.emit(big);
+                } else if is_integer(&word) {
+                    return fail(ParseErrKind::IntegerTooLarge, span, None);
+                } else if let Some(float) = parse_float(&word) {
+                    if !float.is_finite() {
+                        return fail(ParseErrKind::FloatTooLarge, span, None);
+                    }
+
+                    stack.emit(Word::Float(float));
+                } else if let Some(tag) = heredoc_tag(&word) {
+                    match stream.heredoc(tag) {
+                        Ok(text) => stack.emit(Word::Str(text)),
+                        Err(ParseErrKind::BadHeredoc) => {
+                            return fail(ParseErrKind::BadHeredoc, span, None);
+                        },
+                        Err(kind) => return fail(kind, stream.here(), Some(span)),
+                    }
+
+                    // The words before the tag were all on one line.
+                    stack.newline();
+                } else if word == "dict" {
+                    // `dict` is reserved for dict literals, so that it
+                    // can never be mistaken for an atom before a list.
+                    while stream.peek().is_some_and(|ch| ch.is_whitespace()) {
+                        stream.next();
+                    }
+
+                    if stream.next() != Some('{') {
+                        return fail(ParseErrKind::BadDict, span, None);
+                    }
+
+                    stack.push(Some(stream.behind(1)), true);
                 } else {
-                    stack.emit(Word::Atom(word))?;
+                    let atom = Symbol::intern(&word);
+                    let span = if spans { Some(Rc::new(span)) } else { None };
+                    stack.emit(Word::Atom(atom, span));
                 }
             },
         }
     }
 
-    let program = stack.flatten()?;
-    if stack.0.is_empty() {
-        Ok(program)
-    } else {
-        Err(ParseErr::MissingCloseBrace)
+    if let Some(open) = stack.open() {
+        return fail(ParseErrKind::MissingCloseBrace, stream.here(), {
+            Some(open.clone())
+        });
+    }
+
+    Ok(stack.flatten())
+}
+
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    file: Rc<str>,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(file: &str, input: &'a str) -> Self {
+        Cursor {
+            chars: input.chars().peekable(),
+            file: file.into(),
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// The position of the next character, as a zero-length span.
+    fn here(&self) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            col: self.col,
+            len: 0,
+        }
+    }
+
+    /// The last `len` characters consumed, which must share a line.
+    fn behind(&self, len: usize) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            col: self.col - len,
+            len,
+        }
+    }
+
+    /// How many `#`s the raw string opening here has, if the characters
+    /// after an `r` open one: `r"`, `r#"`, `r##"` and so on.
+    fn raw_hashes(&self) -> Option<usize> {
+        let mut hashes = 0;
+
+        for ch in self.chars.clone() {
+            match ch {
+                '#' => hashes += 1,
+                '"' => return Some(hashes),
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    /// Reads the body of a heredoc whose tag has just been consumed, up
+    /// to a line holding only the tag. The lines keep their newlines,
+    /// except the last, and lose the indentation they all share.
+    fn heredoc(&mut self, tag: &str) -> Result<String, ParseErrKind> {
+        let mut line = String::new();
+
+        // The body starts on the line after the tag, so that the tag's
+        // line can hold nothing else.
+        if !self.line(&mut line) {
+            return Err(ParseErrKind::MissingHeredocEnd);
+        } else if !line.trim().is_empty() {
+            return Err(ParseErrKind::BadHeredoc);
+        }
+
+        let mut lines = Vec::new();
+        loop {
+            line.clear();
+            let more = self.line(&mut line);
+
+            if line.trim() == tag {
+                break;
+            } else if !more {
+                return Err(ParseErrKind::MissingHeredocEnd);
+            }
+
+            lines.push(line.clone());
+        }
+
+        let indent = |line: &str| {
+            line.chars().take_while(|ch| ch.is_whitespace()).count()
+        };
+
+        let common = lines.iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| indent(line))
+            .min()
+            .unwrap_or(0);
+
+        Ok(lines.iter().map(|line| {
+            line.chars().skip(common).collect::<String>()
+        }).collect::<Vec<_>>().join("\n"))
+    }
+
+    /// Reads up to the end of the line, consuming but not keeping the
+    /// newline. Says whether there was one.
+    fn line(&mut self, buf: &mut String) -> bool {
+        for ch in self.by_ref() {
+            if ch == '\n' {
+                return true;
+            }
+
+            buf.push(ch);
+        }
+
+        false
+    }
+
+    /// Reads the rest of an escape sequence whose backslash has just
+    /// been consumed. A bad one gives the span of what was read of it.
+    fn escape(&mut self) -> Result<char, Span> {
+        let mut span = self.behind(1);
+        let ch = self.unescape();
+
+        if self.line == span.line {
+            span.len = self.col - span.col;
+        }
+
+        ch.ok_or(span)
+    }
+
+    fn unescape(&mut self) -> Option<char> {
+        Some(match self.next()? {
+            '"' => '"',
+            '\\' => '\\',
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+
+            'u' => {
+                if self.next()? != '{' {
+                    return None;
+                }
+
+                let mut digits = String::new();
+                loop {
+                    match self.next()? {
+                        '}' => break,
+                        ch if ch.is_ascii_hexdigit() && digits.len() < 6 => {
+                            digits.push(ch);
+                        },
+                        _ => return None,
+                    }
+                }
+
+                char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?
+            },
+
+            _ => return None,
+        })
+    }
+}
+
+impl<'a> Iterator for Cursor<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+
+        Some(ch)
     }
 }
 
 struct Stack(Vec<Block>);
 
-type Block = Vec<Line>;
+struct Block {
+    open: Option<Span>,
+    lines: Vec<Line>,
+    dict: bool,
+}
 
 type Line = Vec<Word>;
 
@@ -90,50 +436,142 @@ impl Stack {
         Stack(Vec::with_capacity(n))
     }
 
-    fn push(&mut self) {
-        let mut block = Vec::with_capacity(16);
-        block.push(Vec::with_capacity(16));
-        self.0.push(block);
+    fn push(&mut self, open: Option<Span>, dict: bool) {
+        let mut lines = Vec::with_capacity(16);
+        lines.push(Vec::with_capacity(16));
+        self.0.push(Block { open, lines, dict });
     }
 
-    fn pop(&mut self) -> Result<(), ParseErr> {
-        let list = self.flatten()?;
-        self.emit(Word::List(list.into()))
+    /// Closes the innermost block. A dict whose entries are not all of
+    /// the form `"key" = value` is refused, giving where it was opened.
+    fn pop(&mut self) -> Result<(), Option<Span>> {
+        if self.0.last().is_some_and(|block| block.dict) {
+            let block = self.0.pop().unwrap();
+            let dict = Stack::entries(block.lines).ok_or(block.open)?;
+            self.emit(Word::Dict(dict));
+        } else {
+            let list = self.flatten();
+            self.emit(Word::from(list));
+        }
+
+        Ok(())
     }
 
-    fn newline(&mut self) -> Result<(), ParseErr> {
-        let block = self.0.iter_mut().last()
-            .ok_or(ParseErr::MissingOpenBrace)?;
+    /// Dict entries are read in source order, one per line.
+    fn entries(lines: Vec<Line>) -> Option<OrderMap<String, Word>> {
+        let mut dict = OrderMap::new();
 
-        block.push(Vec::with_capacity(16));
-        Ok(())
+        for line in lines {
+            let mut words = line.into_iter();
+
+            let key = match words.next() {
+                Some(Word::Str(key)) => key,
+                None => continue,
+                _ => return None,
+            };
+
+            match words.next() {
+                Some(Word::Atom(ref eq, _)) if eq.as_str() == "=" => {},
+                _ => return None,
+            }
+
+            match (words.next(), words.next()) {
+                (Some(value), None) => dict.insert(key, value),
+                _ => return None,
+            };
+        }
+
+        Some(dict)
     }
 
-    fn emit(&mut self, word: Word) -> Result<(), ParseErr> {
-        if let Some(block) = self.0.iter_mut().last() {
-            let line = block.iter_mut().last().unwrap();
+    /// Where the innermost unclosed brace was opened, if there is one.
+    fn open(&self) -> Option<&Span> {
+        self.0.last().and_then(|block| block.open.as_ref())
+    }
+
+    fn newline(&mut self) {
+        if let Some(block) = self.0.last_mut() {
+            block.lines.push(Vec::with_capacity(16));
+        }
+    }
+
+    fn emit(&mut self, word: Word) {
+        if let Some(block) = self.0.last_mut() {
+            let line = block.lines.last_mut().unwrap();
             line.push(word);
-            Ok(())
-        } else {
-            Err(ParseErr::MissingOpenBrace)
         }
     }
 
-    fn flatten(&mut self) -> Result<Program, ParseErr> {
-        if let Some(mut block) = self.0.pop() {
-            let total_len = block.iter().map(|line| line.len()).sum();
-            let mut list = Vec::with_capacity(total_len);
-            while let Some(line) = block.pop() {
-                list.extend(line.into_iter());
-            }
-            Ok(list)
-        } else {
-            Err(ParseErr::MissingOpenBrace)
+    fn flatten(&mut self) -> Program {
+        let mut lines = match self.0.pop() {
+            Some(block) => block.lines,
+            None => return Vec::new(),
+        };
+
+        let total_len = lines.iter().map(|line| line.len()).sum();
+        let mut list = Vec::with_capacity(total_len);
+        while let Some(line) = lines.pop() {
+            list.extend(line);
         }
+        list
+    }
+}
+
+/// The tag of a heredoc opened by `word`, as in `<<EOF`.
+fn heredoc_tag(word: &str) -> Option<&str> {
+    let tag = word.strip_prefix("<<")?;
+
+    if !tag.is_empty() && tag.chars().all(|ch| ch.is_alphanumeric() || ch == '_') {
+        Some(tag)
+    } else {
+        None
+    }
+}
+
+/// Whether the word is written as an integer, however large.
+fn is_integer(word: &str) -> bool {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    !digits.is_empty() && digits.chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Reads an integer literal too large for an `i32`.
+#[cfg(feature = "bigint")]
+fn parse_big(word: &str) -> Option<Word> {
+    if !is_integer(word) {
+        return None;
     }
+
+    word.parse::<num_bigint::BigInt>().ok().map(Word::from)
+}
+
+#[cfg(not(feature = "bigint"))]
+fn parse_big(_: &str) -> Option<Word> {
+    None
 }
 
-fn parse_hex(word: String) -> Result<u32, ParseErr> {
+/// Reads a float such as `1.5`, `-2.` or `6e23`. A point or an exponent
+/// is required, so integers never turn into floats. Unlike Rust, words
+/// like `inf` and `NaN` are left as atoms, but a literal too large for
+/// an `f64` comes back infinite.
+fn parse_float(word: &str) -> Option<f64> {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+
+    if !digits.starts_with(|ch: char| ch.is_ascii_digit()) {
+        return None;
+    }
+
+    if !digits.contains(['.', 'e', 'E']) {
+        return None;
+    }
+
+    if !digits.chars().all(|ch| matches!(ch, '0' ..= '9' | '.' | 'e' | 'E' | '+' | '-')) {
+        return None;
+    }
+
+    word.parse::<f64>().ok()
+}
+
+fn parse_hex(word: &str) -> Option<u32> {
     //if word.len() == 3 || word.len() == 4 {
     //    let mut longer = String::with_capacity(word.len() * 2);
     //    for ch in word.chars() {
@@ -143,21 +581,18 @@ fn parse_hex(word: String) -> Result<u32, ParseErr> {
     //    word = longer;
     //}
 
-    u32::from_str_radix(&word, 16).map_err(|_| ParseErr::BadHexLiteral)
+    u32::from_str_radix(word, 16).ok()
 }
 
 fn word_break(a: char, b: char) -> bool {
     fn is_delim(ch: char) -> bool {
-        match ch {
-            '{' | ';' | '}' => true,
-            _ => false,
-        }
+        matches!(ch, '{' | ';' | '}')
     }
 
     match (a, b) {
         (_, s) if s.is_whitespace() => true,
         (_, s) if is_delim(s) => true,
-        ('=', '=') => false,
+        ('=', '=') | ('<', '=') | ('>', '=') => false,
         ('=', _) => true,
         (_, '=') => true,
         _ => false,
@@ -171,6 +606,7 @@ fn funky_word_breaks() {
         ("{}{}{}", "{ } { } { }"),
         ("{+ 1 2}", "{ + 1 2 }"),
         ("foo;bar;baz", "baz bar foo"),
+        ("<=1 >=2", "<= 1 >= 2"),
     ];
 
     for (left, right) in inputs {
@@ -182,3 +618,113 @@ fn funky_word_breaks() {
         }
     }
 }
+
+#[test]
+fn error_positions() {
+    let inputs = vec![
+        ("x = {\n  + 1 2\n", ParseErrKind::MissingCloseBrace, (3, 1), Some((1, 5))),
+        ("echo }", ParseErrKind::MissingOpenBrace, (1, 6), None),
+        ("a\n  echo \"oops", ParseErrKind::MissingEndQuote, (2, 13), Some((2, 8))),
+        ("x = #zz", ParseErrKind::BadHexLiteral, (1, 5), None),
+        ("echo \"a\\qb\"", ParseErrKind::BadEscape, (1, 8), None),
+        ("echo \"\\u{d800}\"", ParseErrKind::BadEscape, (1, 7), None),
+        ("x = r#\"oops\"", ParseErrKind::MissingEndQuote, (1, 13), Some((1, 5))),
+        ("x = <<EOF\n  oops\n", ParseErrKind::MissingHeredocEnd, (3, 1), Some((1, 5))),
+        ("x = <<EOF oops\nEOF", ParseErrKind::BadHeredoc, (1, 5), None),
+        ("x = dict 3", ParseErrKind::BadDict, (1, 5), None),
+        ("x = dict {\n  a = 1\n}", ParseErrKind::BadDict, (1, 10), None),
+        ("x = -1e400", ParseErrKind::FloatTooLarge, (1, 5), None),
+    ];
+
+    for (source, kind, (line, col), open) in inputs {
+        let err = parse_named("test.iv", source).unwrap_err();
+        assert_eq!(err.kind, kind);
+        assert_eq!(&*err.span.file, "test.iv");
+        assert_eq!((err.span.line, err.span.col), (line, col));
+        assert_eq!(err.open.map(|span| (span.line, span.col)), open);
+    }
+}
+
+#[test]
+fn string_escapes() {
+    let source = r#""\"\\\n\t\r\0\u{e9}\u{1F600}""#;
+    let expected = Word::Str("\"\\\n\t\r\0\u{e9}\u{1F600}".to_owned());
+    assert_eq!(parse(source).unwrap(), vec![expected]);
+
+    for bad in &[r#""\u{}""#, r#""\u{1234567}""#, r#""\u12""#, r#""\x""#] {
+        assert_eq!(parse(bad).unwrap_err().kind, ParseErrKind::BadEscape);
+    }
+}
+
+#[test]
+fn raw_strings_and_heredocs() {
+    let inputs = vec![
+        (r#"r"a\b""#, "a\\b"),
+        (r###"r##"say "#hi"#"##"###, "say \"#hi\"#"),
+        ("r\"two\nlines\"", "two\nlines"),
+        ("<<EOF\n    ls -l\n\n      | wc\n    EOF", "ls -l\n\n  | wc"),
+        ("<<END \nEND", ""),
+    ];
+
+    for (source, text) in inputs {
+        assert_eq!(parse(source).unwrap(), vec![Word::Str(text.to_owned())]);
+    }
+
+    // The heredoc ends its line, so the words after it start another.
+    let program = parse("echo <<EOF\n  hi\n  EOF\nbye").unwrap();
+    assert_eq!(program, parse("echo \"hi\"\nbye").unwrap());
+
+    // Atoms that merely look close are left alone.
+    assert_eq!(parse("r << <<a-b").unwrap().len(), 3);
+}
+
+#[test]
+fn float_literals() {
+    let inputs = vec![
+        ("1.5", Word::Float(1.5)),
+        ("-2.", Word::Float(-2.0)),
+        ("6e23", Word::Float(6e23)),
+        ("1.0e-7", Word::Float(1e-7)),
+        ("1", Word::Int(1)),
+        ("inf", Word::atom("inf")),
+        ("NaN", Word::atom("NaN")),
+        ("1.2.3", Word::atom("1.2.3")),
+        ("3000000000.", Word::Float(3e9)),
+        ("1e20", Word::Float(1e20)),
+    ];
+
+    for (source, word) in inputs {
+        assert_eq!(parse(source).unwrap(), vec![word]);
+    }
+}
+
+#[test]
+fn oversized_integers() {
+    for source in &["3000000000", "-99999999999999999999"] {
+        let parsed = parse(source);
+
+        #[cfg(not(feature = "bigint"))]
+        assert_eq!(parsed.unwrap_err().kind, ParseErrKind::IntegerTooLarge);
+
+        #[cfg(feature = "bigint")]
+        assert!(matches!(parsed.unwrap()[..], [Word::Big(_)]));
+    }
+}
+
+#[test]
+fn error_excerpts() {
+    let err = parse_named("test.iv", "x = #zz").unwrap_err();
+    assert_eq!(err.to_string(), "\
+test.iv:1:5: invalid hex format
+  |
+1 | x = #zz
+  |     ^^^");
+
+    // An unclosed brace is shown where it was opened, not at the end.
+    let err = parse_named("test.iv", "x = {\n\t+ 1 2\n").unwrap_err();
+    assert_eq!(err.to_string(), "\
+test.iv:3:1: missing }
+  |
+1 | x = {
+  |     ^ unclosed here");
+}
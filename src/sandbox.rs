@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+
+use super::EvalErr;
+
+/// What a script may do outside the shell: run programs, read files
+/// and read from standard input. The default allows all of it.
+///
+/// ```
+/// use backforth::Sandbox;
+///
+/// let sandbox = Sandbox::locked()
+///     .command(true)
+///     .program("ls")
+///     .load(true)
+///     .root("scripts");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    command: bool,
+    load: bool,
+    prompt: bool,
+    save: bool,
+
+    /// Programs `command` may run, if it may not run just any.
+    programs: Option<Vec<String>>,
+
+    /// Directories `load` and `image-save` may use files beneath, if
+    /// they may not use them anywhere.
+    roots: Option<Vec<PathBuf>>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox::new()
+    }
+}
+
+impl Sandbox {
+    /// A sandbox that allows everything.
+    pub fn new() -> Self {
+        Sandbox {
+            command: true,
+            load: true,
+            prompt: true,
+            save: true,
+            programs: None,
+            roots: None,
+        }
+    }
+
+    /// A sandbox that allows nothing until told otherwise.
+    pub fn locked() -> Self {
+        Sandbox {
+            command: false,
+            load: false,
+            prompt: false,
+            save: false,
+            programs: None,
+            roots: None,
+        }
+    }
+
+    pub fn command(mut self, allow: bool) -> Self {
+        self.command = allow;
+        self
+    }
+
+    pub fn load(mut self, allow: bool) -> Self {
+        self.load = allow;
+        self
+    }
+
+    pub fn prompt(mut self, allow: bool) -> Self {
+        self.prompt = allow;
+        self
+    }
+
+    /// Whether `image-save` may write images to disk.
+    pub fn save(mut self, allow: bool) -> Self {
+        self.save = allow;
+        self
+    }
+
+    /// Lets `command` run the named program. Once any program has been
+    /// named, `command` may run only those, and only by the same name.
+    pub fn program<S: Into<String>>(mut self, name: S) -> Self {
+        self.programs.get_or_insert_with(Vec::new).push(name.into());
+        self
+    }
+
+    /// Lets `load` read and `image-save` write files beneath the given
+    /// directory. Once any root has been given, files outside every
+    /// root are refused.
+    pub fn root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.roots.get_or_insert_with(Vec::new).push(path.into());
+        self
+    }
+
+    pub(crate) fn check_command(&self, name: &str) -> Result<(), EvalErr> {
+        if !self.command {
+            return Err(denied("command"));
+        }
+
+        match self.programs {
+            Some(ref programs) if !programs.iter().any(|p| p == name) => {
+                Err(denied(name))
+            },
+
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_load(&self, path: &str) -> Result<(), EvalErr> {
+        if !self.load {
+            return Err(denied("load"));
+        }
+
+        if self.roots.is_none() {
+            return Ok(());
+        }
+
+        // Resolve links and `..` first, so that neither can be used to
+        // climb out of a root. A path that cannot be resolved is refused
+        // outright rather than saying whether it exists.
+        let resolved = Path::new(path).canonicalize().map_err(|_| denied(path))?;
+        self.check_inside(&resolved, path)
+    }
+
+    pub(crate) fn check_save(&self, path: &str) -> Result<(), EvalErr> {
+        if !self.save {
+            return Err(denied("image-save"));
+        }
+
+        if self.roots.is_none() {
+            return Ok(());
+        }
+
+        // The file may not exist yet, so resolve the directory it would
+        // go in instead. One that does exist may be a link, though.
+        let target = Path::new(path);
+        let resolved = target.canonicalize().or_else(|_| {
+            let name = target.file_name().ok_or(())?;
+            let parent = match target.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+
+            parent.canonicalize().map(|parent| parent.join(name)).map_err(|_| ())
+        }).map_err(|_| denied(path))?;
+
+        self.check_inside(&resolved, path)
+    }
+
+    /// Refuse `resolved` unless it is under a root. The refusal names
+    /// the path as the script gave it, since the resolved one would tell
+    /// the script where links lead and what exists.
+    fn check_inside(&self, resolved: &Path, given: &str) -> Result<(), EvalErr> {
+        let inside = self.roots.iter().flatten().any(|root| {
+            root.canonicalize().is_ok_and(|root| resolved.starts_with(root))
+        });
+
+        if inside {
+            Ok(())
+        } else {
+            Err(denied(given))
+        }
+    }
+
+    pub(crate) fn check_prompt(&self) -> Result<(), EvalErr> {
+        if self.prompt {
+            Ok(())
+        } else {
+            Err(denied("prompt"))
+        }
+    }
+}
+
+fn denied(what: &str) -> EvalErr {
+    EvalErr::PermissionDenied(what.to_owned())
+}
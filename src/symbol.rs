@@ -0,0 +1,78 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+/// An interned atom name. Symbols are cheap to copy, compare and hash;
+/// the name they stand for can always be recovered for display.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+thread_local! {
+    static INTERNED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// How many bytes of new names this thread has interned so far. Names
+/// are never freed, so shells charge the growth to whatever ran.
+pub(crate) fn interned_bytes() -> usize {
+    INTERNED.with(Cell::get)
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Self {
+        let mut table = interner().lock().unwrap();
+
+        if let Some(&sym) = table.ids.get(name) {
+            return sym;
+        }
+
+        // Names live as long as the process, so symbols can hand them
+        // out without holding the lock.
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        let sym = Symbol(table.names.len() as u32);
+        table.names.push(name);
+        table.ids.insert(name, sym);
+        INTERNED.with(|bytes| bytes.set(bytes.get() + name.len()));
+        sym
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().lock().unwrap().names[self.0 as usize]
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl<'a> From<&'a str> for Symbol {
+    fn from(name: &'a str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+#[test]
+fn interning_is_idempotent() {
+    let a = Symbol::intern("swap");
+    let b = Symbol::intern("swap");
+    let c = Symbol::intern("rot");
+
+    assert_eq!(a, b);
+    assert!(a != c);
+    assert_eq!(a.as_str(), "swap");
+    assert_eq!(c.as_str(), "rot");
+    assert_eq!(format!("{:?}", a), "\"swap\"");
+}
@@ -0,0 +1,141 @@
+//! What is left of callers whose frames have been dropped.
+//!
+//! A word that calls another as its last action leaves nothing to come
+//! back to, so its frame is discarded and the callee takes its place.
+//! That keeps loops flat, but the backtrace would lose the caller. Each
+//! frame therefore carries the callers it replaced, newest first. Runs of
+//! the same word, as in a loop, are collapsed into one entry, and only
+//! the newest `MAX` entries are kept.
+
+use std::rc::Rc;
+
+use super::Call;
+
+const MAX: usize = 16;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Tail {
+    call: Call,
+    times: usize,
+    len: usize,
+    next: Option<Rc<Tail>>,
+}
+
+impl Tail {
+    /// Records that `call` made a tail call, on top of the callers it had
+    /// itself replaced.
+    pub fn push(mut tail: Option<Rc<Tail>>, call: Call) -> Option<Rc<Tail>> {
+        if let Some(mut head) = tail {
+            if head.call.name == call.name {
+                // The frame being dropped is usually the only holder, in
+                // which case this counts in place.
+                let head_mut = Rc::make_mut(&mut head);
+                head_mut.times += 1;
+                head_mut.call = call;
+                return Some(head);
+            }
+
+            tail = Some(head);
+        }
+
+        let len = tail.as_ref().map_or(0, |tail| tail.len) + 1;
+
+        let tail = Rc::new(Tail {
+            call,
+            times: 1,
+            len,
+            next: tail,
+        });
+
+        if len > MAX {
+            Some(Tail::truncate(&tail))
+        } else {
+            Some(tail)
+        }
+    }
+
+    /// Pushes `calls` in order, `times` times over, as a loop does that
+    /// goes through them every round. Names must not repeat back to
+    /// back, or else there must be only the one call.
+    pub fn repeat(tail: Option<Rc<Tail>>, calls: &[Call], times: usize)
+        -> Option<Rc<Tail>>
+    {
+        if let [ref call] = *calls {
+            if times == 0 {
+                return tail;
+            }
+
+            // Every push after the first just counts one more.
+            return Tail::push(tail, call.clone()).map(|mut tail| {
+                Rc::make_mut(&mut tail).times += times - 1;
+                tail
+            });
+        }
+
+        // Nothing collapses, so past `MAX` rounds the older ones have all
+        // been pushed out.
+        (0 .. times.min(MAX)).fold(tail, |tail, _| {
+            calls.iter().fold(tail, |tail, call| Tail::push(tail, call.clone()))
+        })
+    }
+
+    /// Puts `upper`'s entries in front of `lower`'s.
+    pub fn append(upper: Option<Rc<Tail>>, lower: Option<Rc<Tail>>)
+        -> Option<Rc<Tail>>
+    {
+        let mut entries = Vec::new();
+        let mut cursor = upper;
+
+        while let Some(tail) = cursor {
+            entries.push((tail.call.clone(), tail.times));
+            cursor = tail.next.clone();
+        }
+
+        entries.into_iter().rev().fold(lower, |lower, (call, times)| {
+            let tail = Tail::push(lower, call);
+            tail.map(|mut tail| {
+                Rc::make_mut(&mut tail).times += times - 1;
+                tail
+            })
+        })
+    }
+
+    /// The callers as they appear in a backtrace, newest first.
+    pub fn calls(tail: &Option<Rc<Tail>>) -> Vec<Call> {
+        let mut calls = Vec::new();
+        let mut cursor = tail.as_ref();
+
+        while let Some(tail) = cursor {
+            calls.push(Call {
+                tail: tail.times,
+                ..tail.call.clone()
+            });
+            cursor = tail.next.as_ref();
+        }
+
+        calls
+    }
+
+    fn truncate(tail: &Rc<Tail>) -> Rc<Tail> {
+        let mut entries = Vec::with_capacity(MAX);
+        let mut cursor = Some(tail);
+
+        while let Some(tail) = cursor.filter(|_| entries.len() < MAX) {
+            entries.push(tail);
+            cursor = tail.next.as_ref();
+        }
+
+        let mut rebuilt: Option<Rc<Tail>> = None;
+        for tail in entries.into_iter().rev() {
+            let len = rebuilt.as_ref().map_or(0, |tail| tail.len) + 1;
+            rebuilt = Some(Rc::new(Tail {
+                call: tail.call.clone(),
+                times: tail.times,
+                len,
+                next: rebuilt,
+            }));
+        }
+
+        rebuilt.unwrap()
+    }
+}
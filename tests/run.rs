@@ -1,12 +1,57 @@
 extern crate backforth;
+extern crate ordermap;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
 
 use backforth::*;
 
-fn run_program(source: &str) -> Vec<Word> {
+fn run_program(source: &str, compiled: bool) -> Result<Vec<Word>, EvalErr> {
+    let mut env = Shell::new();
+    env.set_compiled(compiled);
+    env.load(parse(source).unwrap().into_iter());
+    env.run()?;
+    Ok(env.capture().into())
+}
+
+/// Collects what a shell writes, to be read once it has finished.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+/// Runs a program with `input` as its standard input, and returns what
+/// it wrote to standard output and standard error.
+fn run_output(source: &str, compiled: bool, input: &str) -> (String, String) {
+    let stdout = Output::default();
+    let stderr = Output::default();
+
     let mut env = Shell::new();
-    env.load(parse(&source).unwrap().into_iter());
+    env.set_compiled(compiled);
+    env.set_stdout(stdout.clone());
+    env.set_stderr(stderr.clone());
+    env.set_stdin(io::Cursor::new(input.as_bytes().to_vec()));
+
+    env.load(parse(source).unwrap().into_iter());
     env.run().unwrap();
-    env.capture().into()
+
+    (stdout.text(), stderr.text())
 }
 
 macro_rules! sourcify {
@@ -19,8 +64,10 @@ macro_rules! valid {
     ( $name:ident $(, $value:expr )* ) => {
         #[test]
         fn $name() {
-            let result = run_program(sourcify!("valid", $name));
-            assert_eq!(&result, &[ $( $value ),* ]);
+            for &compiled in &[false, true] {
+                let result = run_program(sourcify!("valid", $name), compiled);
+                assert_eq!(&result.unwrap(), &[ $( $value ),* ]);
+            }
         }
     };
 }
@@ -28,9 +75,25 @@ macro_rules! valid {
 macro_rules! invalid {
     ( $name:ident ) => {
         #[test]
-        #[should_panic]
         fn $name() {
-            let _ = run_program(sourcify!("invalid", $name));
+            for &compiled in &[false, true] {
+                assert!(run_program(sourcify!("invalid", $name), compiled).is_err());
+            }
+        }
+    };
+}
+
+macro_rules! prints {
+    ( $name:ident, $output:expr ) => {
+        mod $name {
+            #[test]
+            fn output() {
+                for &compiled in &[false, true] {
+                    let source = sourcify!("valid", $name);
+                    let (stdout, _) = super::run_output(source, compiled, "");
+                    assert_eq!(stdout, $output);
+                }
+            }
         }
     };
 }
@@ -38,5 +101,932 @@ macro_rules! invalid {
 valid!(hello);
 valid!(factorial, Word::Int(120));
 valid!(countdown, Word::Int(0));
+valid!(backtrace, Word::Int(2));
+valid!(error_value, Word::from("x".to_owned()), Word::from("divide-by-zero".to_owned()));
+valid!(throw, Word::Int(2), Word::from("divide-by-zero".to_owned()), Word::from("caught oops".to_owned()));
+valid!(try_restore, Word::from("cant-understand".to_owned()), Word::Int(1), Word::Int(10), Word::Int(20), Word::Int(30));
+valid!(missing_file, Word::from("recovered".to_owned()), Word::from("recovered".to_owned()));
+valid!(arithmetic, Word::Hex(0), Word::Hex(1), Word::Hex(0x10), Word::Hex(0xffff_ffff), Word::Hex(0xf0), Word::Hex(0xff), Word::Hex(0xc), Word::Int(-5), Word::Int(i32::MAX), Word::Hex(0xffff_ffff), Word::Bool(true), Word::Bool(true), Word::Bool(true), Word::Bool(false), Word::Bool(true), Word::Float(1.5), Word::Hex(2), Word::Int(5), Word::Float(2f64.sqrt()), Word::Int(1024), Word::Int(-1), Word::Int(1));
+valid!(logic, Word::Bool(true), Word::Bool(true), Word::Bool(false), Word::Bool(true), Word::Bool(false), Word::from("empty".to_owned()), Word::Bool(true), Word::Bool(true), Word::Bool(true), Word::Bool(true), Word::Bool(true));
+valid!(floats, Word::from("overflow".to_owned()), Word::from("divide-by-zero".to_owned()), Word::Bool(true), Word::Bool(true), Word::Float(255.0), Word::Hex(2), Word::Int(-2), Word::Float(3.5), Word::Float(1.5));
+
+prints!(hello, "Hello, world\n");
+prints!(countdown, "9\n8\n7\n6\n5\n4\n3\n2\n1\n0\n");
 
 invalid!(divide_by_zero);
+
+#[test]
+fn prompt_reads_injected_input() {
+    let source = "echo strcat \"hi \" prompt \"name? \"";
+    let (stdout, stderr) = run_output(source, false, "you\nrest\n");
+    assert_eq!(stdout, "name? hi you\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn diagnostics_go_to_stderr_if_given() {
+    let source = "inspect swap quote";
+    let expected = "swap ( a -- b ) =\n{\n    1\n    roll\n}\n";
+
+    let (stdout, stderr) = run_output(source, false, "");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, expected);
+
+    // Without a stream of their own they share standard output.
+    let stdout = Output::default();
+    let mut env = Shell::new();
+    env.set_stdout(stdout.clone());
+    env.load(parse(source).unwrap().into_iter());
+    env.run().unwrap();
+    assert_eq!(stdout.text(), expected);
+}
+
+#[test]
+fn errors_point_at_call_site() {
+    let mut env = Shell::new();
+    let source = "x = 1\nfoo = { / x }\n\nfoo 0\n";
+    env.load(parse_named("test.iv", source).unwrap().into_iter());
+
+    match env.run() {
+        Err(EvalErr::At(span, err)) => {
+            assert_eq!(&*span.file, "test.iv");
+            assert_eq!((span.line, span.col), (2, 9));
+            assert!(matches!(*err, EvalErr::DivideByZero));
+        },
+
+        other => panic!("expected a located error, got {:?}", other),
+    }
+}
+
+#[test]
+fn runtime_parse_has_no_spans() {
+    let result = run_program("parse \"foo bar\"", false).unwrap();
+
+    match result[0] {
+        Word::List(ref words) => assert!(words.iter().all(|word| {
+            matches!(*word, Word::Atom(_, None))
+        })),
+        ref other => panic!("expected a list, got {:?}", other),
+    }
+}
+
+#[test]
+fn backtrace_lists_callers() {
+    let mut env = Shell::new();
+    let source = "inner = { / 1 0 }\nouter = { drop inner }\nouter\n";
+    env.load(parse_named("test.iv", source).unwrap().into_iter());
+    assert!(env.run().is_err());
+
+    let trace: Vec<_> = env.backtrace().iter().map(|call| {
+        let span = call.span.as_ref().unwrap();
+        (call.name.as_str(), span.line, span.col)
+    }).collect();
+
+    assert_eq!(trace, vec![("inner", 2, 16), ("outer", 3, 1)]);
+}
+
+#[test]
+fn backtrace_keeps_tail_callers() {
+    let mut env = Shell::new();
+    let source = "inner = { / 1 0 }\nouter = { inner }\ntop = { outer drop }\ntop 7\n";
+    env.load(parse_named("t.iv", source).unwrap().into_iter());
+    assert!(env.run().is_err());
+
+    let trace: Vec<_> = env.backtrace().iter().map(|call| {
+        call.to_string()
+    }).collect();
+
+    assert_eq!(trace, vec![
+        "in inner at t.iv:2:11",
+        "in outer (tail) at t.iv:3:9",
+        "in top (tail) at t.iv:4:1",
+    ]);
+
+    // A word that keeps calling itself is counted rather than repeated.
+    let mut env = Shell::new();
+    let source = "down = { if < 0 pick 2 { down + -1 } { / 1 0 } }\ndown 3\n";
+    env.load(parse_named("t.iv", source).unwrap().into_iter());
+    assert!(env.run().is_err());
+
+    let trace: Vec<_> = env.backtrace().iter().map(|call| {
+        call.to_string()
+    }).collect();
+
+    assert_eq!(trace, vec!["in down (tail, 4 times) at t.iv:1:26"]);
+
+}
+
+#[test]
+fn backtrace_names_the_stdlib() {
+    let mut env = Shell::new();
+    env.load(parse_named("t.iv", "while { true } { / 1 0 }\n").unwrap().into_iter());
+    assert!(env.run().is_err());
+
+    let trace: Vec<_> = env.backtrace().iter().map(|call| {
+        call.to_string()
+    }).collect();
+
+    assert_eq!(trace, vec![
+        "in when (tail) at stdlib:46:9",
+        "in while (tail) at t.iv:1:1",
+    ]);
+}
+
+#[test]
+fn compiled_bodies_see_rebound_primitives() {
+    let source = "
+        bump = {
+            drop 5 6
+            drop = { + 1 }
+            drop 10
+        }
+
+        bump
+        bump
+    ";
+
+    let tree = run_program(source, false).unwrap();
+    let compiled = run_program(source, true).unwrap();
+    assert_eq!(tree, compiled);
+    assert_eq!(tree, vec![11, 6, 6, 11, 6].into_iter().map(Word::Int).collect::<Vec<_>>());
+}
+
+#[test]
+fn compiled_loops_match_tree() {
+    let programs = [
+        // Rebinding `while` from inside its own body.
+        "count = {
+            while { < 0 dup } {
+                debug + -1
+                if == 2 pick 2 { while = { \"stopped\" drop drop } } {}
+            }
+        }
+        count 5",
+
+        // Leaving a loop by throwing.
+        "upto = {
+            try {
+                loop { if == 10 pick 2 { throw \"done\" } { + 1 } }
+            } { drop }
+        }
+        upto 0",
+
+        // A macro taking the body of a loop as its argument.
+        "grab = { quote }
+        f = { while { < 0 dup } { + -1 } grab }
+        f 3",
+
+        // Branches the compiler guessed wrong.
+        "choose = { if and { true } { false } { \"yes\" } { \"no\" } }
+        choose",
+
+        // A loop that runs out of stack partway.
+        "bad = { while {} {} }
+        bad",
+
+        // Nested lowering.
+        "sum = {
+            swap 0
+            while { < 0 dup } {
+                if < 5 pick 2 { swap + over swap debug } {}
+                + -1
+            }
+            drop
+        }
+        sum 10",
+    ];
+
+    for program in &programs {
+        let tree = run_program(program, false).map_err(|err| err.to_string());
+        let compiled = run_program(program, true).map_err(|err| err.to_string());
+        assert_eq!(tree, compiled, "{}", program);
+
+        if tree.is_ok() {
+            assert_eq!(run_output(program, false, ""), run_output(program, true, ""));
+        }
+    }
+}
+
+#[test]
+fn compiled_backtraces_match_tree() {
+    let programs = [
+        // The error dict lists the rounds the loop went through.
+        "f = { while { < 0 dup } { + -1 if == 1 pick 2 { / 1 0 } {} } }
+        try { f 3 } { get \"trace\" }",
+
+        // More rounds than the backtrace keeps.
+        "f = { while { < 0 dup } { + -1 if == 1 pick 2 { / 1 0 } {} } }
+        f 40",
+
+        "f = { loop { loop { + -1 if == 0 pick 2 { / 1 0 } {} } } }
+        f 5",
+
+        // A word called from a branch that ends the loop's body.
+        "g = { / 1 0 }
+        f = { while { < 0 dup } { + -1 if == 1 pick 2 { g } {} } }
+        f 5",
+
+        // A loop that the branch it sits in ends with.
+        "f = { if true { while { < 0 dup } { backtrace + -1 } } {} }
+        f 2",
+    ];
+
+    let trace = |program: &str, compiled: bool| {
+        let mut env = Shell::new();
+        env.set_compiled(compiled);
+        env.load(parse(program).unwrap().into_iter());
+        let result = env.run().map_err(|err| err.to_string());
+
+        let calls: Vec<_> = env.backtrace().iter().map(|call| {
+            call.to_string()
+        }).collect();
+
+        (result, calls, env.capture())
+    };
+
+    for program in &programs {
+        let tree = trace(program, false);
+        assert!(!tree.1.is_empty() || !tree.2.is_empty(), "{}", program);
+        assert_eq!(tree, trace(program, true), "{}", program);
+    }
+}
+
+/// Where the two backends may differ: fuel goes per instruction rather
+/// than per word, and an inlined loop adds no frames to the code stack.
+#[test]
+fn compiled_fuel_and_depth_differ() {
+    let source = "f = { while { < 0 dup } { + -1 } }\nf 10";
+
+    let run = |compiled: bool| {
+        let mut env = Shell::new();
+        env.set_compiled(compiled);
+        env.set_limits(Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
+        });
+        env.load(parse(source).unwrap().into_iter());
+        env.run().unwrap();
+        (10_000 - env.fuel_left().unwrap(), env.peak_depth())
+    };
+
+    let (tree_fuel, tree_depth) = run(false);
+    let (compiled_fuel, compiled_depth) = run(true);
+    assert!(compiled_fuel < tree_fuel);
+    assert!(compiled_depth < tree_depth);
+}
+
+#[test]
+fn loops_run_in_constant_depth() {
+    let peak = |iterations: i32, compiled: bool| {
+        let mut env = Shell::new();
+        env.set_compiled(compiled);
+
+        let source = format!("
+            countdown = {{
+                while {{ < 0 dup }} {{
+                    try {{ + -1 }} {{ rethrow }}
+                }}
+            }}
+
+            spin = {{
+                loop {{
+                    + -1
+                    when == 0 pick 1 {{ bye }}
+                }}
+            }}
+
+            countdown {0}
+            drop
+            spin {0}
+        ", iterations);
+
+        env.load(parse(&source).unwrap().into_iter());
+        env.run().unwrap();
+        env.peak_depth()
+    };
+
+    for &compiled in &[false, true] {
+        assert_eq!(peak(10, compiled), peak(25_000, compiled));
+    }
+}
+
+fn run_limited(source: &str, limits: Limits) -> (Result<(), EvalErr>, Shell) {
+    let mut env = Shell::new();
+    env.set_limits(limits);
+    env.load(parse(source).unwrap().into_iter());
+    (env.run(), env)
+}
+
+#[test]
+fn limits_stop_runaway_scripts() {
+    let cases = vec![
+        ("try { loop {} } { drop }", Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
+        }, Limit::Fuel),
+
+        ("loop { 1 }", Limits {
+            data_depth: Some(100),
+            ..Limits::default()
+        }, Limit::DataDepth),
+
+        ("deeper = { + 1 deeper }\ndeeper 0", Limits {
+            code_depth: Some(100),
+            ..Limits::default()
+        }, Limit::CodeDepth),
+
+        ("\"\"\nloop { strcat \"more\" }", Limits {
+            heap_bytes: Some(4096),
+            ..Limits::default()
+        }, Limit::HeapBytes),
+
+        // Atoms made up at runtime are never freed, so they count too.
+        ("0\nloop { drop parse strcat \"made-up-\" show dup + 1 }", Limits {
+            heap_bytes: Some(4096),
+            ..Limits::default()
+        }, Limit::HeapBytes),
+    ];
+
+    for (source, limits, limit) in cases {
+        match run_limited(source, limits).0 {
+            Err(EvalErr::LimitExceeded(hit)) => assert_eq!(hit, limit),
+            other => panic!("expected {:?} limit, got {:?}", limit, other),
+        }
+    }
+}
+
+#[test]
+fn fuel_left_after_run() {
+    let limits = Limits {
+        fuel: Some(100),
+        ..Limits::default()
+    };
+
+    let (result, env) = run_limited("+ 1 2", limits);
+    assert!(result.is_ok());
+    assert_eq!(env.fuel_left(), Some(97));
+
+    let (result, env) = run_limited("+ 1 2", Limits::default());
+    assert!(result.is_ok());
+    assert_eq!(env.fuel_left(), None);
+
+    // Running dry stops before the next word, which a refuelled run
+    // then starts with.
+    let (result, mut env) = run_limited("+ 1 2", Limits {
+        fuel: Some(2),
+        ..Limits::default()
+    });
+    assert!(matches!(result, Err(EvalErr::LimitExceeded(Limit::Fuel))));
+
+    env.set_limits(Limits::default());
+    assert!(env.run().is_ok());
+    assert_eq!(env.capture(), vec![Word::Int(3)]);
+
+    for &compiled in &[false, true] {
+        let mut env = Shell::new();
+        env.set_compiled(compiled);
+        env.set_limits(Limits {
+            fuel: Some(50),
+            ..Limits::default()
+        });
+
+        let source = "sum = { while { < 0 pick 1 } { + over swap + -1 swap } }\nsum 0 10";
+        env.load(parse(source).unwrap().into_iter());
+
+        while let Err(EvalErr::LimitExceeded(Limit::Fuel)) = env.run() {
+            env.set_limits(Limits {
+                fuel: Some(50),
+                ..Limits::default()
+            });
+        }
+
+        assert_eq!(env.capture(), vec![Word::Int(45), Word::Int(0)]);
+    }
+}
+
+#[test]
+fn call_after_fuel_stop() {
+    for &compiled in &[false, true] {
+        let mut env = Shell::new();
+        env.set_compiled(compiled);
+        env.set_limits(Limits {
+            fuel: Some(6),
+            ..Limits::default()
+        });
+
+        // Stops just inside the `try`, whose handler is still pending.
+        env.load(parse("1 2 3\ntry { oops drop } { drop }").unwrap().into_iter());
+        assert!(matches!(env.run(), Err(EvalErr::LimitExceeded(Limit::Fuel))));
+
+        env.set_limits(Limits::default());
+        env.call("drop", None).unwrap();
+        env.call("drop", None).unwrap();
+
+        // The failing body puts back what the calls took, as it would
+        // anything else taken from beneath it.
+        assert!(env.run().is_ok());
+        assert_eq!(env.capture(), vec![Word::Int(1), Word::Int(2), Word::Int(3)]);
+    }
+}
+
+fn run_sandboxed(source: &str, sandbox: Sandbox) -> Result<Vec<Word>, EvalErr> {
+    let mut env = Shell::new();
+    env.set_sandbox(sandbox);
+    env.load(parse(source).unwrap().into_iter());
+    env.run()?;
+    Ok(env.capture().into())
+}
+
+#[test]
+fn sandbox_denials_are_catchable() {
+    let kind = |source: &str, sandbox: Sandbox| {
+        let source = format!("try {{ {} }} {{ get \"kind\" }}", source);
+        run_sandboxed(&source, sandbox).unwrap()
+    };
+
+    let denied = vec![Word::from("permission-denied".to_owned())];
+
+    assert_eq!(kind("command \"true\" {}", Sandbox::locked()), denied);
+    assert_eq!(kind("prompt \"> \"", Sandbox::locked()), denied);
+    assert_eq!(kind("load \"Cargo.toml\"", Sandbox::locked()), denied);
+    assert_eq!(kind("image-save \"never.img\"", Sandbox::locked()), denied);
+
+    let only_echo = Sandbox::new().program("echo");
+    assert_eq!(kind("command \"true\" {}", only_echo), denied);
+
+    let only_tests = Sandbox::new().root("tests/valid");
+    assert_eq!(kind("load \"Cargo.toml\"", only_tests.clone()), denied);
+    assert_eq!(kind("load \"tests/valid/../../Cargo.toml\"", only_tests.clone()), denied);
+    assert_eq!(kind("load \"tests/valid/missing.iv\"", only_tests.clone()), denied);
+    assert_eq!(kind("image-save \"tests/escaped.img\"", only_tests.clone()), denied);
+    assert_eq!(kind("image-save \"tests/valid/../escaped.img\"", only_tests), denied);
+    assert!(!Path::new("tests/escaped.img").exists());
+}
+
+#[test]
+fn sandbox_names_the_path_as_given() {
+    let refused = |source: &str| {
+        let mut err = run_sandboxed(source, Sandbox::new().root("tests/valid")).unwrap_err();
+        while let EvalErr::At(_, inner) = err {
+            err = *inner;
+        }
+
+        match err {
+            EvalErr::PermissionDenied(what) => what,
+            other => panic!("expected a denial, got {:?}", other),
+        }
+    };
+
+    assert_eq!(refused("load \"tests/valid/../../Cargo.toml\""), "tests/valid/../../Cargo.toml");
+    assert_eq!(refused("load \"tests/valid/missing.iv\""), "tests/valid/missing.iv");
+    assert_eq!(refused("image-save \"tests/valid/../escaped.img\""), "tests/valid/../escaped.img");
+
+    // A link out of the roots is not followed in the message either.
+    #[cfg(unix)]
+    {
+        let link = std::env::temp_dir().join(format!("backforth-link-{}", std::process::id()));
+        std::os::unix::fs::symlink(std::fs::canonicalize("Cargo.toml").unwrap(), &link).unwrap();
+        let given = link.display().to_string();
+        let what = refused(&format!("load \"{}\"", given));
+        std::fs::remove_file(&link).unwrap();
+        assert_eq!(what, given);
+    }
+}
+
+#[test]
+fn sandbox_allows_what_it_lists() {
+    let sandbox = Sandbox::locked()
+        .load(true)
+        .root("tests/valid");
+
+    let result = run_sandboxed("len lines load \"tests/valid/hello.\\\\iv\"", sandbox);
+    assert_eq!(result.unwrap(), vec![Word::Int(2)]);
+
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("backforth-root-{}.img", std::process::id()));
+    let source = format!("image-save \"{}\"", path.display());
+
+    let result = run_sandboxed(&source, Sandbox::new().root(&dir));
+    assert!(result.is_ok());
+    assert!(path.exists());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn native_words() {
+    let stderr = Output::default();
+
+    let mut env = Shell::new();
+    env.set_stderr(stderr.clone());
+
+    let unary = TypeSpec {
+        input: 1,
+        output: 1,
+        exact: true,
+    };
+
+    env.register("square", unary, |stack| {
+        let n = match stack.pop()? {
+            Word::Int(n) => n,
+            other => return Err(EvalErr::WrongType(other, TypeName::Int)),
+        };
+
+        stack.push(n * n);
+        Ok(())
+    });
+
+    let source = "
+        fourth = { square square }
+        inspect square quote
+        inspect fourth quote
+        fourth 3
+        try { square \"x\" } { get \"kind\" }
+    ";
+
+    env.load(parse(source).unwrap().into_iter());
+    env.run().unwrap();
+
+    assert_eq!(env.capture(), vec![
+        Word::from("wrong-type".to_owned()),
+        Word::Int(81),
+    ]);
+
+    assert_eq!(stderr.text(), "\
+        square ( a -- b ) = <NATIVE>\n\
+        fourth ( a -- b ) =\n\
+        {\n    square\n    square\n}\n");
+}
+
+#[test]
+fn embedding_api() {
+    use std::convert::TryFrom;
+
+    let mut env = Shell::new();
+
+    env.define("square", parse("* dup").unwrap().into()).unwrap();
+    env.define("greeting", Word::from("hello".to_owned())).unwrap();
+    assert!(env.words().any(|name| name.as_str() == "square"));
+
+    env.call("square", vec![Word::Int(7)]).unwrap();
+    assert_eq!(env.pop_as::<i32>().unwrap(), 49);
+
+    env.call("-", vec![Word::Int(10), Word::Int(3)]).unwrap();
+    assert_eq!(env.peek(0), Some(&Word::Int(7)));
+    assert_eq!(env.pop_as::<u32>().unwrap(), 7);
+
+    env.push(Word::Float(2.7));
+    assert!(env.pop_as::<i32>().is_err());
+    assert_eq!(env.pop_as::<f64>().unwrap(), 2.7);
+
+    env.push(Word::from(parse("1 2 3").unwrap()));
+    assert!(env.pop_as::<String>().is_err());
+    assert_eq!(env.pop_as::<Vec<i32>>().unwrap(), vec![1, 2, 3]);
+
+    env.call("greeting", None).unwrap();
+    assert_eq!(env.pop_as::<String>().unwrap(), "hello");
+
+    assert!(env.undefine("greeting"));
+    assert!(!env.undefine("greeting"));
+    assert!(env.call("greeting", None).is_err());
+    assert!(env.pop().is_err());
+
+    assert_eq!(Vec::<String>::try_from(Word::from(vec![])).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn builder_options() {
+    let bare = ShellBuilder::new().stdlib(false).build().unwrap();
+    assert!(bare.words().all(|name| name.as_str() != "dup"));
+    assert!(bare.words().any(|name| name.as_str() == "pick"));
+
+    let mut renamed = ShellBuilder::new()
+        .stdlib(false)
+        .builtins(vec!["=", "+", "pick"])
+        .rename("+", "plus")
+        .prelude("twice.iv", "twice = { plus pick 0 }")
+        .build()
+        .unwrap();
+
+    let mut names: Vec<_> = renamed.words().map(|name| name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, vec!["=", "pick", "plus", "popeh", "twice"]);
+
+    renamed.call("twice", vec![Word::Int(21)]).unwrap();
+    assert_eq!(renamed.pop_as::<i32>().unwrap(), 42);
+
+    let mut from_file = ShellBuilder::new()
+        .prelude_file("tests/valid/factorial.\\iv")
+        .build()
+        .unwrap();
+    assert_eq!(from_file.pop_as::<i32>().unwrap(), 120);
+}
+
+#[test]
+fn builder_errors() {
+    let broken = ShellBuilder::new().prelude("broken.iv", "x = {").build();
+    match broken {
+        Err(EvalErr::BadParse(err)) => assert_eq!(&*err.span.file, "broken.iv"),
+        other => panic!("expected a parse error, got {:?}", other.err()),
+    }
+
+    let failing = ShellBuilder::new().prelude("failing.iv", "/ 1 0").build();
+    assert_eq!(failing.err().map(|err| err.kind()), Some("divide-by-zero"));
+
+    let unknown = ShellBuilder::new().rename("nope", "yes").build();
+    assert_eq!(unknown.err().map(|err| err.kind()), Some("cant-understand"));
+
+    let missing = ShellBuilder::new().prelude_file("tests/missing.iv").build();
+    assert_eq!(missing.err().map(|err| err.kind()), Some("io"));
+}
+
+#[test]
+fn image_round_trip() {
+    let mut dict = ordermap::OrderMap::new();
+    dict.insert("name".to_owned(), Word::from("value".to_owned()));
+    dict.insert("nested".to_owned(), Word::from(vec![Word::Int(1), Word::Hex(2)]));
+
+    let words = vec![
+        Word::atom("swap"),
+        Word::Int(-7),
+        Word::Hex(0xdead_beef),
+        Word::Float(-0.125),
+        Word::Bool(false),
+        Word::from("text with \"quotes\"".to_owned()),
+        Word::from(parse("{ 1 2 } three \"four\"").unwrap()),
+        Word::from(vec![]),
+        Word::Dict(dict),
+    ];
+
+    let mut env = Shell::new();
+    env.define("square", parse("* dup").unwrap().into()).unwrap();
+    for word in words.iter().rev() {
+        env.push(word.clone());
+    }
+
+    let mut image = Vec::new();
+    env.save_image(&mut image).unwrap();
+
+    let mut restored = ShellBuilder::new().stdlib(false).build().unwrap();
+    restored.load_image(&image[..]).unwrap();
+
+    assert_eq!(restored.capture(), env.capture());
+    assert_eq!(Vec::from(restored.capture()), words);
+    assert_eq!(restored.words().collect::<Vec<_>>(), env.words().collect::<Vec<_>>());
+
+    restored.call("square", vec![Word::Int(12)]).unwrap();
+    assert_eq!(restored.pop_as::<i32>().unwrap(), 144);
+
+    let mut again = Vec::new();
+    restored.save_image(&mut again).unwrap();
+    assert_eq!(again.len(), image.len());
+}
+
+#[test]
+fn image_resumes_pending_code() {
+    let path = std::env::temp_dir().join(format!("backforth-{}.img", std::process::id()));
+    let source = format!("
+        counter = 41
+        image-save \"{}\"
+        counter = + 1 counter
+    ", path.display());
+
+    run_program(&source, false).unwrap();
+
+    let mut env = ShellBuilder::new().stdlib(false).build().unwrap();
+    env.load_image(std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    env.run().unwrap();
+    env.call("counter", None).unwrap();
+    assert_eq!(env.pop_as::<i32>().unwrap(), 42);
+}
+
+#[test]
+fn image_errors() {
+    let mut image = Vec::new();
+    Shell::new().save_image(&mut image).unwrap();
+
+    let mut env = Shell::new();
+
+    // The version follows the eight-byte magic string.
+    let mut newer = image.clone();
+    newer[8] += 1;
+    assert_eq!(env.load_image(&newer[..]).err().map(|err| err.kind()), Some("bad-image"));
+
+    let truncated = &image[.. image.len() / 2];
+    assert_eq!(env.load_image(truncated).err().map(|err| err.kind()), Some("bad-image"));
+
+    assert_eq!(env.load_image(&b"not an image"[..]).err().map(|err| err.kind()), Some("bad-image"));
+}
+
+/// A small xorshift generator, so the round-trip test needs no crates
+/// and fails the same way every time.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn string(&mut self) -> String {
+        let chars = ['a', 'z', ' ', '"', '\\', '{', '}', ';', '#', '\n', '\t', '\r', '\0', '\u{7}', 'é', '='];
+        (0 .. self.below(8)).map(|_| chars[self.below(chars.len())]).collect()
+    }
+
+    fn word(&mut self, depth: usize) -> Word {
+        let atoms = [
+            "x", "+", "=", "((", "))", "a-b", "dup", "inf", "NaN", "1.2.3",
+            "true", "12", "1.5", "#ff", "dict", "#", "a b", "{", "", "r\"\"",
+        ];
+        let kinds = if depth == 0 { 5 } else { 7 };
+
+        match self.below(kinds) {
+            0 if self.below(4) == 0 => Word::Bool(self.below(2) == 0),
+            0 => Word::atom(atoms[self.below(atoms.len())]),
+            1 if cfg!(feature = "bigint") && self.below(2) == 0 => {
+                let digits = format!("-{}{}", self.next(), self.next());
+                parse_word(&digits).unwrap()
+            },
+
+            1 => Word::Int(self.next() as i32),
+            2 => Word::Hex(self.next() as u32),
+            3 => Word::Str(self.string()),
+
+            4 => match self.below(8) {
+                0 => Word::Float([f64::NAN, f64::INFINITY, f64::NEG_INFINITY][self.below(3)]),
+                _ => Word::Float(f64::from_bits(self.next())),
+            },
+
+            5 => Word::from((0 .. self.below(4)).map(|_| {
+                self.word(depth - 1)
+            }).collect::<Vec<_>>()),
+
+            _ => Word::Dict((0 .. self.below(4)).map(|_| {
+                (self.string(), self.word(depth - 1))
+            }).collect()),
+        }
+    }
+}
+
+#[test]
+fn display_round_trips() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    let (mut read, mut refused) = (0, 0);
+
+    for _ in 0 .. 4000 {
+        let word = rng.word(3);
+        let text = word.to_string();
+
+        if word.reads_back() {
+            assert_eq!(parse_word(&text).ok(), Some(word), "{}", text);
+            read += 1;
+        } else {
+            assert_ne!(parse_word(&text).ok(), Some(word), "{}", text);
+            refused += 1;
+        }
+    }
+
+    assert!(read > 1000 && refused > 100, "{} read, {} refused", read, refused);
+}
+
+#[test]
+fn show_and_read() {
+    let program = r#"
+        read show dict { "k\"ey" = { 1 #2 "a\\b" }; "x" = dict {} }
+        show "say \"hi\""
+        read "{ 3 2 1 }"
+    "#;
+
+    for &compiled in &[false, true] {
+        let result = run_program(program, compiled).unwrap();
+
+        let mut dict = ordermap::OrderMap::new();
+        dict.insert("k\"ey".to_owned(), Word::from(vec![
+            Word::Int(1), Word::Hex(2), Word::Str("a\\b".to_owned()),
+        ]));
+        dict.insert("x".to_owned(), Word::Dict(ordermap::OrderMap::new()));
+
+        assert_eq!(result, [
+            Word::from(vec![Word::Int(3), Word::Int(2), Word::Int(1)]),
+            Word::Str(r#""say \"hi\"""#.to_owned()),
+            Word::Dict(dict),
+        ]);
+
+        let err = run_program("read \"1 2\"", compiled).unwrap_err();
+        assert_eq!(err.kind(), "bad-parse");
+    }
+
+    // Only the host can make words with no source form, and `show`
+    // refuses them rather than give text that reads back as another.
+    for word in [Word::Float(f64::NAN), Word::from(vec![Word::atom("true")])] {
+        let mut env = Shell::new();
+        env.load(vec![Word::atom("show"), word].into_iter());
+        assert_eq!(env.run().map_err(|err| err.kind()), Err("cant-coerce"));
+    }
+}
+
+#[test]
+fn prompt_reads_until_complete() {
+    let program = r#"eval parse prompt-more "echo <<EOF""#;
+    let input = "  first\n    second\n  EOF\nnot read\n";
+
+    for &compiled in &[false, true] {
+        let (stdout, _) = run_output(program, compiled, input);
+        assert_eq!(stdout, "... ... ... first\n  second\n");
+
+        let result = run_program(r#"incomplete "}" incomplete "x = { 1""#, compiled);
+        assert_eq!(result.unwrap(), [Word::Bool(false), Word::Bool(true)]);
+    }
+}
+
+#[test]
+fn arithmetic_errors() {
+    let programs = [
+        ("% 1 0", "divide-by-zero"),
+        ("pow 2 -1", "cant-coerce"),
+        ("& 1.5 1", "wrong-type"),
+        ("& -1 1", "cant-coerce"),
+        // Only `int` and `hex` truncate floats.
+        ("pick 0.9 5", "cant-coerce"),
+        ("roll 1.5 1 2", "cant-coerce"),
+        ("min \"a\" 1", "cant-coerce"),
+    ];
+
+    for &compiled in &[false, true] {
+        for &(program, kind) in &programs {
+            let err = run_program(program, compiled).unwrap_err();
+            assert_eq!(err.kind(), kind, "{}", program);
+        }
+    }
+}
+
+#[test]
+#[cfg(not(feature = "bigint"))]
+fn integer_overflow() {
+    let programs = [
+        "* 65536 65536",
+        "+ 2147483647 1",
+        "- -2 2147483647",
+        "~ -2147483648",
+        "/ -2147483648 -1",
+        "pow 3 20",
+        "abs -2147483648",
+        "+ #80000000 1",
+        "* #ffffffff #ffffffff",
+    ];
+
+    for &compiled in &[false, true] {
+        for program in &programs {
+            let err = run_program(program, compiled).unwrap_err();
+            assert_eq!(err.kind(), "overflow", "{}", program);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "bigint")]
+fn big_integers() {
+    let program = format!("{}\nshow factorial 25", sourcify!("valid", factorial));
+
+    for &compiled in &[false, true] {
+        let result = run_program(&program, compiled).unwrap();
+        assert_eq!(result, [
+            Word::Str("15511210043330985984000000".to_owned()),
+            Word::Int(120),
+        ]);
+
+        // Results that fit an `Int` again become one.
+        let result = run_program("- * 65536 65536 4294967295", compiled).unwrap();
+        assert_eq!(result, [Word::Int(1)]);
+
+        let result = run_program("~ -2147483648", compiled).unwrap();
+        assert_eq!(result, [parse_word("2147483648").unwrap()]);
+
+        // Results too big to work out quickly overflow instead.
+        for &program in &["pow 7 50000000", "pow pow 2 40000 2", "* pow 2 40000 pow 2 40000"] {
+            let err = run_program(program, compiled).unwrap_err();
+            assert_eq!(err.kind(), "overflow", "{}", program);
+        }
+
+        let result = run_program("pow -1 50000001", compiled).unwrap();
+        assert_eq!(result, [Word::Int(-1)]);
+    }
+
+    let big = parse_word("-123456789012345678901234567890").unwrap();
+
+    let mut env = Shell::new();
+    env.push(big.clone());
+
+    let mut image = Vec::new();
+    env.save_image(&mut image).unwrap();
+    env.load_image(&image[..]).unwrap();
+    assert_eq!(env.pop().unwrap(), big);
+}